@@ -1,8 +1,7 @@
 extern crate rascal;
 
 fn main() {
-    use rascal::types::hl;
-    let platform = hl::get_platforms()[0];
-    let device = platform.get_devices()[0];
+    use rascal::hl;
+    let (platform, device) = hl::DeviceSelector::new().select().unwrap();
     let context = platform.create_context(&[device]).unwrap();
 }
@@ -1,6 +1,24 @@
 extern crate rascal;
 extern crate opencl;
 
+fn print_rascal_platforms() -> rascal::Result<()> {
+    for platform in try!(rascal::hl::get_platforms()).iter() {
+        println!("Platform: {}", try!(platform.name()));
+        println!("Platform Version: {}", try!(platform.version()));
+        println!("Vendor:   {}", try!(platform.vendor()));
+        println!("Profile:  {}", try!(platform.profile()));
+        println!("Available extensions: {}", try!(platform.extensions()));
+        println!("Available devices:");
+        for device in try!(platform.get_devices()).iter() {
+            println!("   Name: {}", try!(device.name()));
+            println!("   Type: {:?}", try!(device.device_type()));
+            println!("   Profile: {}", try!(device.profile()));
+            println!("   Compute Units: {}", try!(device.num_compute_units()));
+        }
+    }
+    Ok(())
+}
+
 fn main() {
     {
         use opencl::hl;
@@ -23,19 +41,8 @@ fn main() {
     println!("");
     {
         println!("Using rascal...");
-        for platform in rascal::hl::get_platforms().iter() {
-            println!("Platform: {}", platform.name());
-            println!("Platform Version: {}", platform.version());
-            println!("Vendor:   {}", platform.vendor());
-            println!("Profile:  {}", platform.profile());
-            println!("Available extensions: {}", platform.extensions());
-            println!("Available devices:");
-            for device in platform.get_devices().iter() {
-                println!("   Name: {}", device.name());
-                println!("   Type: {:?}", device.device_type());
-                println!("   Profile: {}", device.profile());
-                println!("   Compute Units: {}", device.num_compute_units());
-            }
+        if let Err(err) = print_rascal_platforms() {
+            println!("Error: {}", err);
         }
     }
 }
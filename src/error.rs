@@ -0,0 +1,116 @@
+use std::error;
+use std::fmt;
+use num;
+use opencl::cl;
+use opencl::cl::CLStatus;
+use super::ll::DeviceBuildLog;
+
+/// The status code an OpenCL call failed with.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Status {
+    Known(CLStatus),
+    /// A code `CLStatus` doesn't cover, such as one defined by a vendor extension.
+    Unknown(cl::cl_int),
+}
+
+impl Status {
+    pub fn from_code(code: cl::cl_int) -> Status {
+        match num::FromPrimitive::from_i32(code) {
+            Some(status) => Status::Known(status),
+            None => Status::Unknown(code),
+        }
+    }
+
+    pub fn code(&self) -> cl::cl_int {
+        match *self {
+            Status::Known(status) => status as cl::cl_int,
+            Status::Unknown(code) => code,
+        }
+    }
+}
+
+impl From<CLStatus> for Status {
+    fn from(status: CLStatus) -> Status {
+        Status::Known(status)
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Status::Known(status) => write!(f, "{:?}", status),
+            Status::Unknown(code) => write!(f, "unknown status {}", code),
+        }
+    }
+}
+
+/// An error from an OpenCL call, or from a call rascal refused to make because its arguments
+/// were bound to fail.
+#[derive(Debug, Clone)]
+pub struct Error {
+    function: &'static str,
+    status: Status,
+    message: Option<String>,
+    build_logs: Vec<DeviceBuildLog>,
+}
+
+impl Error {
+    pub fn new<S: Into<Status>>(function: &'static str, status: S) -> Error {
+        Error {
+            function: function,
+            status: status.into(),
+            message: None,
+            build_logs: Vec::new(),
+        }
+    }
+
+    pub fn with_message<M: Into<String>>(mut self, message: M) -> Error {
+        self.message = Some(message.into());
+        self
+    }
+
+    pub fn with_build_logs(mut self, build_logs: Vec<DeviceBuildLog>) -> Error {
+        self.build_logs = build_logs;
+        self
+    }
+
+    /// The OpenCL API function that failed, e.g. `"clBuildProgram"`.
+    pub fn function(&self) -> &'static str {
+        self.function
+    }
+
+    pub fn status(&self) -> Status {
+        self.status
+    }
+
+    pub fn message(&self) -> Option<&str> {
+        self.message.as_ref().map(|message| &message[..])
+    }
+
+    /// The per-device build logs, if the error came from building a program.
+    pub fn build_logs(&self) -> &[DeviceBuildLog] {
+        &self.build_logs[..]
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{} failed with {}", self.function, self.status));
+        if let Some(ref message) = self.message {
+            try!(write!(f, ": {}", message));
+        }
+        for log in self.build_logs.iter() {
+            try!(write!(f, "\n{}", log));
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match self.message {
+            Some(ref message) => message,
+            None => "OpenCL call failed",
+        }
+    }
+}
@@ -1,5 +1,27 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::collections::btree_set::{self, BTreeSet};
+use std::env;
+use std::fmt;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::iter::repeat;
+use std::marker::PhantomData;
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::future::{Future, IntoFuture};
+use std::pin::Pin;
+use std::process;
+use std::sync::Arc;
+use std::sync::atomic::{self, AtomicUsize};
+use std::task::{self, Poll};
+use opencl::cl::CLStatus;
+use regex::Regex;
+#[cfg(feature = "serde")]
+use serde::{Serialize, Serializer};
 use super::ll;
-use super::Result;
+use super::{Error, Result};
 
 #[derive(Debug, Copy, Clone)]
 pub struct Platform(ll::PlatformId);
@@ -10,79 +32,1583 @@ pub struct Device(ll::DeviceId);
 #[derive(Debug, Clone)]
 pub struct Context(ll::Context);
 
-pub fn get_platforms() -> Vec<Platform> {
-    ll::get_platform_ids().unwrap().into_iter().map(Platform).collect()
+#[derive(Debug, Clone)]
+pub struct Queue(ll::CommandQueue);
+
+/// A device buffer holding `len()` values of type `T`.
+#[derive(Debug)]
+pub struct Buffer<T: ll::Pod> {
+    mem: ll::Mem,
+    len: usize,
+    prot: ll::MemProt,
+    context: Context,
+    _marker: PhantomData<T>,
+}
+
+/// A `Buffer` whose storage is a borrowed host slice (see `Context::create_buffer_use_host`).
+#[derive(Debug)]
+pub struct HostBuffer<'a, T: ll::Pod + 'a> {
+    buffer: Buffer<T>,
+    _host: PhantomData<&'a mut [T]>,
+}
+
+/// A non-blocking read of a whole `Buffer`, resolving to the data once the read has finished.
+/// Dropping it early blocks until the driver is done writing into the destination.
+pub struct PendingRead<T: ll::Pod> {
+    data: Option<Vec<T>>,
+    event: ll::EventFuture,
+}
+
+#[derive(Debug, Clone)]
+pub struct Program(ll::Program);
+
+#[derive(Debug, Clone)]
+pub struct Kernel(ll::Kernel);
+
+/// Returns every available platform, or an empty list if no OpenCL implementation is
+/// installed.
+pub fn get_platforms() -> Result<Vec<Platform>> {
+    ll::get_platform_ids().map(|ids| ids.into_iter().map(Platform).collect())
 }
 
 impl Context {
-    
+    /// The devices the context was created for.
+    pub fn devices(&self) -> Result<Vec<Device>> {
+        ll::get_context_info(&self.0, ll::ContextInfoDevices)
+            .map(|ids| ids.into_iter().map(Device).collect())
+    }
+
+    pub fn reference_count(&self) -> Result<u32> {
+        ll::get_context_info(&self.0, ll::ContextInfoClUint::ReferenceCount)
+    }
+
+    pub fn properties(&self) -> Result<ll::ContextProperties> {
+        ll::get_context_info(&self.0, ll::ContextInfoProperties)
+    }
+
+    fn wrap_buffer<T: ll::Pod>(&self, mem: ll::Mem, prot: ll::MemProt, len: usize) -> Buffer<T> {
+        Buffer {
+            mem: mem,
+            len: len,
+            prot: prot,
+            context: self.clone(),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Creates a device buffer with room for `len` values of type `T`.
+    pub fn create_buffer<T: ll::Pod>(&self, prot: ll::MemProt, len: usize) -> Result<Buffer<T>> {
+        let size = try!(buffer_size::<T>(len));
+        let mem = try!(ll::create_mem_device_buffer(&self.0, prot, size));
+        Ok(self.wrap_buffer(mem, prot, len))
+    }
+
+    /// Creates a device buffer holding a copy of `data`.
+    pub fn create_buffer_from_slice<T: ll::Pod>(&self, prot: ll::MemProt, data: &[T])
+        -> Result<Buffer<T>>
+    {
+        let mem = try!(ll::create_mem_buffer_copy_host(&self.0, prot, data));
+        Ok(self.wrap_buffer(mem, prot, data.len()))
+    }
+
+    /// Creates a buffer with room for `len` values of type `T` in host-accessible memory
+    /// allocated by the driver.
+    pub fn create_buffer_host_alloc<T: ll::Pod>(&self, prot: ll::MemProt, len: usize)
+        -> Result<Buffer<T>>
+    {
+        let size = try!(buffer_size::<T>(len));
+        let mem = try!(ll::create_mem_buffer_alloc_host(&self.0, prot, size));
+        Ok(self.wrap_buffer(mem, prot, len))
+    }
+
+    /// Creates a buffer that uses `data` as its storage, which avoids copies on devices that
+    /// share memory with the host. `data` stays borrowed for as long as the buffer is alive.
+    ///
+    /// Unsafe because commands still using the buffer when it is dropped aren't waited for:
+    /// the caller must make sure every command using it has finished (e.g. by finishing the
+    /// queues it was used on) before dropping it, as with `ll::create_mem_buffer_use_host`.
+    pub unsafe fn create_buffer_use_host<'a, T: ll::Pod>(&self, prot: ll::MemProt,
+        data: &'a mut [T])
+        -> Result<HostBuffer<'a, T>>
+    {
+        let len = data.len();
+        let mem = try!(ll::create_mem_buffer_use_host(&self.0, prot, data));
+        Ok(HostBuffer { buffer: self.wrap_buffer(mem, prot, len), _host: PhantomData })
+    }
+
+    pub fn create_user_event(&self) -> Result<ll::UserEvent> {
+        ll::create_user_event(&self.0)
+    }
+
+    pub fn create_queue(&self, device: &Device) -> Result<Queue> {
+        self.create_queue_with_properties(device, ll::queue_properties::QueueProperties::empty())
+    }
+
+    pub fn create_queue_with_properties(&self, device: &Device,
+        properties: ll::queue_properties::QueueProperties)
+        -> Result<Queue>
+    {
+        ll::create_command_queue(&self.0, device.0, properties).map(Queue)
+    }
+
+    /// Creates a program from `source` and builds it for every device in the context.
+    pub fn build_program(&self, source: &str, options: &BuildOptions) -> Result<Program> {
+        let program = try!(ll::create_program_with_source(&self.0, &[source]));
+        try!(ll::build_program(&program, &[], &try!(options.render("clBuildProgram"))));
+        Ok(Program(program))
+    }
+
+    /// Compiles `source` without linking it, for use with `link_programs`. `headers` are the
+    /// programs that `#include "<name>"` in `source` refers to, each under the name given with
+    /// it; they're never compiled themselves, so they can be created with
+    /// `create_program`.
+    pub fn compile_program(&self, source: &str, options: &BuildOptions,
+        headers: &[(&str, &Program)])
+        -> Result<Program>
+    {
+        let program = try!(ll::create_program_with_source(&self.0, &[source]));
+        let headers: Vec<_> = headers.iter().map(|&(name, header)| (name, &header.0)).collect();
+        let options = try!(options.render("clCompileProgram"));
+        try!(ll::compile_program(&program, &[], &options, &headers));
+        Ok(Program(program))
+    }
+
+    /// Links programs from `compile_program` into a single program for every device of the
+    /// context.
+    pub fn link_programs(&self, programs: &[&Program], options: &BuildOptions)
+        -> Result<Program>
+    {
+        let programs: Vec<_> = programs.iter().map(|program| &program.0).collect();
+        let options = try!(options.render("clLinkProgram"));
+        ll::link_program(&self.0, &[], &options, &programs).map(Program)
+    }
+
+    /// Creates a program from `source` without building it, such as a header for
+    /// `compile_program`.
+    pub fn create_program(&self, source: &str) -> Result<Program> {
+        ll::create_program_with_source(&self.0, &[source]).map(Program)
+    }
+}
+
+impl Queue {
+    pub fn context(&self) -> Result<Context> {
+        ll::get_command_queue_info(&self.0, ll::CommandQueueInfoContext).map(Context)
+    }
+
+    pub fn device(&self) -> Result<Device> {
+        ll::get_command_queue_info(&self.0, ll::CommandQueueInfoDevice).map(Device)
+    }
+
+    pub fn reference_count(&self) -> Result<u32> {
+        ll::get_command_queue_info(&self.0, ll::CommandQueueInfoReferenceCount)
+    }
+
+    pub fn properties(&self) -> Result<ll::queue_properties::QueueProperties> {
+        ll::get_command_queue_info(&self.0, ll::CommandQueueInfoProperties)
+    }
+
+    /// Runs `kernel` over `global` work-items, in work-groups of size `local` if given.
+    pub fn launch<G: Into<ll::NDRange>>(&self, kernel: &Kernel, global: G,
+        local: Option<ll::NDRange>)
+        -> Result<ll::Event>
+    {
+        ll::enqueue_nd_range_kernel(&self.0, &kernel.0, None, global.into(), local, &[])
+    }
+}
+
+impl<T: ll::Pod> Buffer<T> {
+    /// The number of values (not bytes) in the buffer.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn prot(&self) -> ll::MemProt {
+        self.prot
+    }
+
+    pub fn context(&self) -> &Context {
+        &self.context
+    }
+
+    /// Copies the whole buffer into `data`, which must be exactly `len()` long.
+    pub fn read_into(&self, queue: &Queue, data: &mut [T]) -> Result<()> {
+        if data.len() != self.len {
+            return Err(Error::new("clEnqueueReadBuffer", CLStatus::CL_INVALID_VALUE)
+                .with_message(format!("expected {} values, got {}", self.len, data.len())));
+        }
+        ll::enqueue_read_buffer(&queue.0, &self.mem, 0, data, &[]).map(|_| ())
+    }
+
+    /// Overwrites the whole buffer with `data`, which must be exactly `len()` long.
+    pub fn write_from(&self, queue: &Queue, data: &[T]) -> Result<()> {
+        if data.len() != self.len {
+            return Err(Error::new("clEnqueueWriteBuffer", CLStatus::CL_INVALID_VALUE)
+                .with_message(format!("expected {} values, got {}", self.len, data.len())));
+        }
+        ll::enqueue_write_buffer(&queue.0, &self.mem, 0, data, &[]).map(|_| ())
+    }
+
+    /// Maps the whole buffer into host memory for reading.
+    ///
+    /// Unsafe because kernels the buffer was passed to keep using it regardless of the
+    /// mapping: as with `ll::enqueue_map_buffer`, the caller must make sure no command writes
+    /// to the buffer until the mapping is dropped.
+    pub unsafe fn map<'a>(&'a self, queue: &'a Queue) -> Result<ll::MappedRead<'a, T>> {
+        ll::enqueue_map_buffer(&queue.0, &self.mem, 0, self.len, &[])
+    }
+
+    /// Maps the whole buffer into host memory for reading and writing.
+    ///
+    /// Unsafe for the same reason as `map`, except that no command may read the buffer
+    /// either until the mapping is dropped.
+    pub unsafe fn map_mut<'a>(&'a mut self, queue: &'a Queue) -> Result<ll::MappedWrite<'a, T>> {
+        ll::enqueue_map_buffer_mut(&queue.0, &self.mem, 0, self.len, &[])
+    }
+
+    pub fn to_vec(&self, queue: &Queue) -> Result<Vec<T>> {
+        let mut data = zeroed_vec(self.len);
+        try!(self.read_into(queue, &mut data[..]));
+        Ok(data)
+    }
+
+    /// Like `to_vec`, but returns as soon as the read is enqueued.
+    pub fn to_vec_async(&self, queue: &Queue) -> Result<PendingRead<T>> {
+        let mut data = zeroed_vec(self.len);
+        // `PendingRead` owns `data` (whose heap storage never moves) and waits for the read
+        // before letting go of it.
+        let event = try!(unsafe {
+            ll::enqueue_read_buffer_nonblocking(&queue.0, &self.mem, 0, &mut data[..], &[])
+        });
+        Ok(PendingRead { data: Some(data), event: event.into_future() })
+    }
+}
+
+fn zeroed_vec<T: ll::Pod>(len: usize) -> Vec<T> {
+    // zeroed is fine, every bit pattern is a valid `Pod`.
+    repeat(unsafe { mem::zeroed() }).take(len).collect()
+}
+
+fn buffer_size<T>(len: usize) -> Result<usize> {
+    match len.checked_mul(mem::size_of::<T>()) {
+        Some(size) => Ok(size),
+        None => Err(Error::new("clCreateBuffer", CLStatus::CL_INVALID_BUFFER_SIZE)
+            .with_message(format!("{} values overflow the buffer size", len))),
+    }
+}
+
+impl<'a, T: ll::Pod> Deref for HostBuffer<'a, T> {
+    type Target = Buffer<T>;
+    fn deref(&self) -> &Buffer<T> {
+        &self.buffer
+    }
+}
+
+impl<'a, T: ll::Pod> DerefMut for HostBuffer<'a, T> {
+    fn deref_mut(&mut self) -> &mut Buffer<T> {
+        &mut self.buffer
+    }
+}
+
+impl<T: ll::Pod> Future for PendingRead<T> {
+    type Output = Result<Vec<T>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<Result<Vec<T>>> {
+        let this = &mut *self;
+        match Pin::new(&mut this.event).poll(cx) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(Ok(())) => {
+                let data = this.data.take()
+                    .expect("Rascal: Polled PendingRead after it completed!");
+                Poll::Ready(Ok(data))
+            }
+            Poll::Ready(Err(err)) => {
+                // the read may still be in flight (e.g. if the completion callback couldn't be
+                // registered), so its destination can't be freed until the driver is done.
+                let _ = this.event.event().wait();
+                let finished = match this.event.event().status() {
+                    Ok(ll::EventStatus::Complete) | Ok(ll::EventStatus::Error(_)) => true,
+                    _ => false,
+                };
+                let data = this.data.take();
+                if !finished {
+                    // no way of knowing when it's safe, so never free it.
+                    mem::forget(data);
+                }
+                Poll::Ready(Err(err))
+            }
+        }
+    }
+}
+
+impl<T: ll::Pod> Drop for PendingRead<T> {
+    fn drop(&mut self) {
+        if self.data.is_some() {
+            let _ = self.event.event().wait();
+        }
+    }
+}
+
+unsafe impl<T: ll::Pod> ll::KernelArg for Buffer<T> {
+    fn arg_size(&self) -> usize {
+        ll::KernelArg::arg_size(&self.mem)
+    }
+
+    fn arg_ptr(&self) -> *const ::libc::c_void {
+        ll::KernelArg::arg_ptr(&self.mem)
+    }
+}
+
+impl Program {
+    pub fn create_kernel(&self, name: &str) -> Result<Kernel> {
+        ll::create_kernel(&self.0, name).map(Kernel)
+    }
+
+    pub fn create_kernels(&self) -> Result<Vec<Kernel>> {
+        ll::create_kernels_in_program(&self.0)
+            .map(|kernels| kernels.into_iter().map(Kernel).collect())
+    }
+
+    pub fn source(&self) -> Result<String> {
+        ll::get_program_info(&self.0, ll::ProgramInfoSource)
+    }
+
+    pub fn devices(&self) -> Result<Vec<Device>> {
+        ll::get_program_info(&self.0, ll::ProgramInfoDevices)
+            .map(|ids| ids.into_iter().map(Device).collect())
+    }
+
+    /// The size of the built binary for each of `devices()`, in the same order.
+    pub fn binary_sizes(&self) -> Result<Vec<usize>> {
+        ll::get_program_info(&self.0, ll::ProgramInfoBinarySizes)
+    }
+
+    pub fn kernel_names(&self) -> Result<Vec<String>> {
+        ll::get_program_info(&self.0, ll::ProgramInfoKernelNames)
+    }
+}
+
+impl Kernel {
+    pub fn set_arg<A: ll::KernelArg + ?Sized>(&self, index: u32, arg: &A) -> Result<()> {
+        self.0.set_arg(index, arg)
+    }
+
+    pub fn function_name(&self) -> Result<String> {
+        ll::get_kernel_info(&self.0, ll::KernelInfoString::FunctionName)
+    }
+
+    pub fn num_args(&self) -> Result<u32> {
+        ll::get_kernel_info(&self.0, ll::KernelInfoClUint::NumArgs)
+    }
+
+    pub fn attributes(&self) -> Result<String> {
+        ll::get_kernel_info(&self.0, ll::KernelInfoString::Attributes)
+    }
+
+    /// The largest work-group this kernel can be launched with on `device`.
+    pub fn work_group_size(&self, device: &Device) -> Result<usize> {
+        ll::get_kernel_work_group_info(
+            &self.0, device.0, ll::KernelWorkGroupInfoSize::WorkGroupSize)
+    }
+
+    pub fn preferred_work_group_size_multiple(&self, device: &Device) -> Result<usize> {
+        ll::get_kernel_work_group_info(
+            &self.0, device.0, ll::KernelWorkGroupInfoSize::PreferredWorkGroupSizeMultiple)
+    }
+
+    pub fn local_mem_size(&self, device: &Device) -> Result<u64> {
+        ll::get_kernel_work_group_info(
+            &self.0, device.0, ll::KernelWorkGroupInfoClUlong::LocalMemSize)
+    }
+
+    pub fn private_mem_size(&self, device: &Device) -> Result<u64> {
+        ll::get_kernel_work_group_info(
+            &self.0, device.0, ll::KernelWorkGroupInfoClUlong::PrivateMemSize)
+    }
+
+    /// The work-group size the kernel source requires, if it declares one.
+    pub fn compile_work_group_size(&self, device: &Device) -> Result<Option<[usize; 3]>> {
+        let sizes = try!(ll::get_kernel_work_group_info(
+            &self.0, device.0, ll::KernelWorkGroupInfoCompileWorkGroupSize));
+        Ok(if sizes == [0, 0, 0] { None } else { Some(sizes) })
+    }
 }
 
 impl Platform {
-    pub fn get_devices(&self) -> Vec<Device> {
-        ll::get_device_ids(self.0, ll::device_type::ALL).unwrap()
-            .into_iter().map(Device).collect()
+    pub fn get_devices(&self) -> Result<Vec<Device>> {
+        ll::get_device_ids(self.0, ll::device_type::ALL)
+            .map(|ids| ids.into_iter().map(Device).collect())
     }
 
+    /// Creates a context for `devices` which writes any errors it reports to stderr.
     pub fn create_context(&self, devices: &[Device]) -> Result<Context> {
+        self.create_context_with_notify(devices, ll::LogNotify)
+    }
+
+    /// Like `create_context`, but passes errors the context reports to `notify` instead of
+    /// writing them to stderr.
+    ///
+    /// `notify`, and anything it owns, is leaked: OpenCL gives no reliable way to learn when
+    /// the context is destroyed. If you create contexts repeatedly, share one notifier between
+    /// them (e.g. a closure around an `Arc`) instead of making a new one for each.
+    pub fn create_context_with_notify<N: ll::ContextNotify + 'static>(&self, devices: &[Device],
+        notify: N)
+        -> Result<Context>
+    {
         // yes, this double-buffers (and so does ll::create_context).
         // this is because transmute is the devil, and I will avoid it for
         // as long as I can.
         let devices: Vec<_> = devices.iter().map(|d| d.0).collect();
-        ll::create_context(self.0, &devices[..]).map(Context)
+        let properties = ll::ContextProperties::new().platform(self.0);
+        ll::create_context(&properties, &devices[..], notify).map(Context)
+    }
+
+    /// Creates a context for every device on this platform of type `device_type`, e.g.
+    /// `platform.create_context_for(ll::device_type::GPU)`.
+    pub fn create_context_for(&self, device_type: ll::DeviceType) -> Result<Context> {
+        let properties = ll::ContextProperties::new().platform(self.0);
+        ll::create_context_from_type(&properties, device_type, ll::LogNotify).map(Context)
+    }
+
+    pub fn name(&self) -> Result<String> {
+        ll::get_platform_info(self.0, ll::PlatformInfo::Name)
     }
 
-    pub fn name(&self) -> String {
-        ll::get_platform_info(self.0, ll::PlatformInfo::Name).unwrap()
+    pub fn version(&self) -> Result<String> {
+        ll::get_platform_info(self.0, ll::PlatformInfo::Version)
     }
 
-    pub fn version(&self) -> String {
-        ll::get_platform_info(self.0, ll::PlatformInfo::Version).unwrap()
+    pub fn profile(&self) -> Result<String> {
+        ll::get_platform_info(self.0, ll::PlatformInfo::Profile)
     }
 
-    pub fn profile(&self) -> String {
-        ll::get_platform_info(self.0, ll::PlatformInfo::Profile).unwrap()
+    pub fn vendor(&self) -> Result<String> {
+        ll::get_platform_info(self.0, ll::PlatformInfo::Vendor)
     }
 
-    pub fn vendor(&self) -> String {
-        ll::get_platform_info(self.0, ll::PlatformInfo::Vendor).unwrap()
+    pub fn extensions(&self) -> Result<String> {
+        ll::get_platform_info(self.0, ll::PlatformInfo::Extensions)
     }
 
-    pub fn extensions(&self) -> String {
-        ll::get_platform_info(self.0, ll::PlatformInfo::Extensions).unwrap()
+    /// The highest OpenCL version the platform supports.
+    pub fn cl_version(&self) -> Result<ClVersion> {
+        let version = try!(self.version());
+        parse_version("clGetPlatformInfo", &version)
+    }
+
+    pub fn extension_set(&self) -> Result<Extensions> {
+        self.extensions().map(|extensions| Extensions::parse(&extensions))
     }
 }
 
 impl Device {
-    pub fn name(&self) -> String {
-        ll::get_device_info(self.0, ll::DeviceInfoString::Name).unwrap()
+    pub fn name(&self) -> Result<String> {
+        ll::get_device_info(self.0, ll::DeviceInfoString::Name)
+    }
+
+    pub fn profile(&self) -> Result<String> {
+        ll::get_device_info(self.0, ll::DeviceInfoString::Profile)
+    }
+
+    pub fn vendor(&self) -> Result<String> {
+        ll::get_device_info(self.0, ll::DeviceInfoString::Vendor)
+    }
+
+    pub fn device_version(&self) -> Result<String> {
+        ll::get_device_info(self.0, ll::DeviceInfoString::DeviceVersion)
+    }
+
+    pub fn driver_version(&self) -> Result<String> {
+        ll::get_device_info(self.0, ll::DeviceInfoString::DriverVersion)
+    }
+
+    pub fn extensions(&self) -> Result<String> {
+        ll::get_device_info(self.0, ll::DeviceInfoString::Extensions)
+    }
+
+    /// The OpenCL version the device supports.
+    pub fn cl_version(&self) -> Result<ClVersion> {
+        let version = try!(self.device_version());
+        parse_version("clGetDeviceInfo", &version)
+    }
+
+    pub fn extension_set(&self) -> Result<Extensions> {
+        self.extensions().map(|extensions| Extensions::parse(&extensions))
+    }
+
+    pub fn device_type(&self) -> Result<ll::DeviceType> {
+        ll::get_device_info(self.0, ll::DeviceInfoDeviceType)
+    }
+
+    pub fn num_compute_units(&self) -> Result<usize> {
+        ll::get_device_info(self.0, ll::DeviceInfoClUint::MaxComputeUnits)
+            .map(|units| units as usize)
+    }
+
+    /// In MHz.
+    pub fn max_clock_frequency(&self) -> Result<u32> {
+        ll::get_device_info(self.0, ll::DeviceInfoClUint::MaxClockFrequency)
+    }
+
+    /// In bytes.
+    pub fn global_mem_size(&self) -> Result<u64> {
+        ll::get_device_info(self.0, ll::DeviceInfoClUlong::GlobalMemSize)
+    }
+
+    /// In bytes.
+    pub fn local_mem_size(&self) -> Result<u64> {
+        ll::get_device_info(self.0, ll::DeviceInfoClUlong::LocalMemSize)
+    }
+
+    /// The largest buffer that can be allocated, in bytes.
+    pub fn max_mem_alloc_size(&self) -> Result<u64> {
+        ll::get_device_info(self.0, ll::DeviceInfoClUlong::MaxMemAllocSize)
+    }
+
+    pub fn max_work_group_size(&self) -> Result<usize> {
+        ll::get_device_info(self.0, ll::DeviceInfoSize::MaxWorkGroupSize)
+    }
+
+    pub fn max_work_item_sizes(&self) -> Result<Vec<usize>> {
+        ll::get_device_info(self.0, ll::DeviceInfoMaxWorkItemSizes)
+    }
+
+    /// Queries everything there is to know about the device in one go.
+    pub fn capabilities(&self) -> Result<DeviceCapabilities> {
+        use ll::DeviceInfoBool as B;
+        use ll::DeviceInfoString as S;
+        use ll::DeviceInfoClUint as U;
+        use ll::DeviceInfoClUlong as L;
+        use ll::DeviceInfoSize as Z;
+        let id = self.0;
+        let flag = |info| ll::get_device_info(id, info).map(|b| b != 0);
+        Ok(DeviceCapabilities {
+            name: try!(ll::get_device_info(id, S::Name)),
+            vendor: try!(ll::get_device_info(id, S::Vendor)),
+            vendor_id: try!(ll::get_device_info(id, U::VendorId)),
+            profile: try!(ll::get_device_info(id, S::Profile)),
+            device_version: try!(ll::get_device_info(id, S::DeviceVersion)),
+            driver_version: try!(ll::get_device_info(id, S::DriverVersion)),
+            extensions: try!(ll::get_device_info(id, S::Extensions)),
+            device_type: try!(ll::get_device_info(id, ll::DeviceInfoDeviceType)),
+
+            available: try!(flag(B::Available)),
+            compiler_available: try!(flag(B::CompilerAvailable)),
+            endian_little: try!(flag(B::EndianLittle)),
+            error_correction_support: try!(flag(B::ErrorCorrectionSupport)),
+            image_support: try!(flag(B::ImageSupport)),
+
+            max_compute_units: try!(ll::get_device_info(id, U::MaxComputeUnits)),
+            max_clock_frequency: try!(ll::get_device_info(id, U::MaxClockFrequency)),
+            address_bits: try!(ll::get_device_info(id, U::AddressBits)),
+            execution_capabilities: try!(
+                ll::get_device_info(id, ll::DeviceInfoExecutionCapabilities)),
+            queue_properties: try!(ll::get_device_info(id, ll::DeviceInfoQueueProperties)),
+            profiling_timer_resolution: try!(ll::get_device_info(id, Z::ProfilingTimerResolution)),
+
+            global_mem_size: try!(ll::get_device_info(id, L::GlobalMemSize)),
+            global_mem_cache_size: try!(ll::get_device_info(id, L::GlobalMemCacheSize)),
+            global_mem_cacheline_size: try!(ll::get_device_info(id, U::GlobalMemCachelineSize)),
+            global_mem_cache_type: try!(ll::get_device_info(id, ll::DeviceInfoGlobalMemCacheType)),
+            local_mem_size: try!(ll::get_device_info(id, L::LocalMemSize)),
+            local_mem_type: try!(ll::get_device_info(id, ll::DeviceInfoLocalMemType)),
+            max_mem_alloc_size: try!(ll::get_device_info(id, L::MaxMemAllocSize)),
+            max_constant_buffer_size: try!(ll::get_device_info(id, L::MaxConstantBufferSize)),
+            max_constant_args: try!(ll::get_device_info(id, U::MaxConstantArgs)),
+            mem_base_addr_align: try!(ll::get_device_info(id, U::MemBaseAddrAlign)),
+            min_data_type_align_size: try!(ll::get_device_info(id, U::MinDataTypeAlignSize)),
+
+            max_work_group_size: try!(ll::get_device_info(id, Z::MaxWorkGroupSize)),
+            max_work_item_sizes: try!(ll::get_device_info(id, ll::DeviceInfoMaxWorkItemSizes)),
+            max_parameter_size: try!(ll::get_device_info(id, Z::MaxParameterSize)),
+
+            max_read_image_args: try!(ll::get_device_info(id, U::MaxReadImageArgs)),
+            max_write_image_args: try!(ll::get_device_info(id, U::MaxWriteImageArgs)),
+            max_samplers: try!(ll::get_device_info(id, U::MaxSamplers)),
+            image2d_max_width: try!(ll::get_device_info(id, Z::Image2dMaxWidth)),
+            image2d_max_height: try!(ll::get_device_info(id, Z::Image2dMaxHeight)),
+            image3d_max_width: try!(ll::get_device_info(id, Z::Image3dMaxWidth)),
+            image3d_max_height: try!(ll::get_device_info(id, Z::Image3dMaxHeight)),
+            image3d_max_depth: try!(ll::get_device_info(id, Z::Image3dMaxDepth)),
+
+            single_fp_config: try!(ll::get_device_info(id, ll::DeviceInfoFpConfig::Single)),
+            // devices without fp64 or fp16 support may reject these queries outright.
+            double_fp_config: ll::get_device_info(id, ll::DeviceInfoFpConfig::Double)
+                .unwrap_or(ll::fp_config::FpConfig::empty()),
+            half_fp_config: ll::get_device_info(id, ll::DeviceInfoFpConfig::Half)
+                .unwrap_or(ll::fp_config::FpConfig::empty()),
+
+            preferred_vector_width_char: try!(ll::get_device_info(id, U::PreferredVectorWidthChar)),
+            preferred_vector_width_short: try!(
+                ll::get_device_info(id, U::PreferredVectorWidthShort)),
+            preferred_vector_width_int: try!(ll::get_device_info(id, U::PreferredVectorWidthInt)),
+            preferred_vector_width_long: try!(ll::get_device_info(id, U::PreferredVectorWidthLong)),
+            preferred_vector_width_float: try!(
+                ll::get_device_info(id, U::PreferredVectorWidthFloat)),
+            preferred_vector_width_double: try!(
+                ll::get_device_info(id, U::PreferredVectorWidthDouble)),
+        })
+    }
+}
+
+/// An OpenCL version, as reported in the form
+/// `OpenCL <major>.<minor> <vendor-specific information>`. Versions compare and order by
+/// `major`, then `minor`; `vendor_info` is ignored.
+#[derive(Debug, Clone)]
+pub struct ClVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub vendor_info: String,
+}
+
+impl ClVersion {
+    pub fn new(major: u32, minor: u32) -> ClVersion {
+        ClVersion { major: major, minor: minor, vendor_info: String::new() }
+    }
+
+    /// Parses platform and device versions (`OpenCL 1.2 ...`) as well as OpenCL C versions
+    /// (`OpenCL C 1.2 ...`).
+    pub fn parse(version: &str) -> Option<ClVersion> {
+        let version = version.trim();
+        let rest = if version.starts_with("OpenCL C ") {
+            &version["OpenCL C ".len()..]
+        } else if version.starts_with("OpenCL ") {
+            &version["OpenCL ".len()..]
+        } else {
+            return None;
+        };
+        let (number, vendor_info) = match rest.find(char::is_whitespace) {
+            Some(idx) => (&rest[..idx], rest[idx..].trim()),
+            None => (rest, ""),
+        };
+        let mut parts = number.splitn(2, '.');
+        let major = parts.next().and_then(|major| major.parse().ok());
+        let minor = parts.next().and_then(|minor| minor.parse().ok());
+        match (major, minor) {
+            (Some(major), Some(minor)) => Some(ClVersion {
+                major: major,
+                minor: minor,
+                vendor_info: vendor_info.to_string(),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Whether this is version `major.minor` or later.
+    pub fn at_least(&self, major: u32, minor: u32) -> bool {
+        (self.major, self.minor) >= (major, minor)
+    }
+}
+
+impl PartialEq for ClVersion {
+    fn eq(&self, other: &ClVersion) -> bool {
+        (self.major, self.minor) == (other.major, other.minor)
+    }
+}
+
+impl Eq for ClVersion {}
+
+impl PartialOrd for ClVersion {
+    fn partial_cmp(&self, other: &ClVersion) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ClVersion {
+    fn cmp(&self, other: &ClVersion) -> Ordering {
+        (self.major, self.minor).cmp(&(other.major, other.minor))
+    }
+}
+
+impl Hash for ClVersion {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (self.major, self.minor).hash(state)
+    }
+}
+
+impl fmt::Display for ClVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "OpenCL {}.{}", self.major, self.minor));
+        if !self.vendor_info.is_empty() {
+            try!(write!(f, " {}", self.vendor_info));
+        }
+        Ok(())
+    }
+}
+
+fn parse_version(function: &'static str, version: &str) -> Result<ClVersion> {
+    match ClVersion::parse(version) {
+        Some(version) => Ok(version),
+        None => Err(Error::new(function, CLStatus::CL_INVALID_VALUE)
+            .with_message(format!("malformed OpenCL version {:?}", version))),
+    }
+}
+
+/// A set of extension names, such as `cl_khr_fp64`.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Extensions(BTreeSet<String>);
+
+impl Extensions {
+    /// Parses the space-separated list reported by platforms and devices.
+    pub fn parse(extensions: &str) -> Extensions {
+        Extensions(extensions.split_whitespace().map(|name| name.to_string()).collect())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.0.contains(name)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> btree_set::Iter<String> {
+        self.0.iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a Extensions {
+    type Item = &'a String;
+    type IntoIter = btree_set::Iter<'a, String>;
+    fn into_iter(self) -> btree_set::Iter<'a, String> {
+        self.0.iter()
+    }
+}
+
+/// Picks the best device across every platform, subject to some requirements.
+///
+/// ```ignore
+/// let (platform, device) = try!(DeviceSelector::new()
+///     .device_type(ll::device_type::GPU)
+///     .min_version(1, 2)
+///     .require_extension("cl_khr_fp64")
+///     .select());
+/// ```
+///
+/// Among the devices meeting the requirements, GPUs rank above accelerators, which rank above
+/// CPUs; ties go to the most compute units times clock frequency, then the most global memory.
+/// Requirements not covered here can be added with `filter`, and `rank_by` ranks devices by a
+/// score of your own ahead of all that:
+///
+/// ```ignore
+/// let (platform, device) = try!(DeviceSelector::new()
+///     .filter(|device| device.capabilities().map(|caps| caps.image_support))
+///     .rank_by(|device| device.max_work_group_size().map(|size| size as u64))
+///     .select());
+/// ```
+///
+/// Unless disabled with `no_env_override`, setting the `RASCAL_DEVICE` environment variable
+/// overrides the selection (and the requirements): either to `<platform>:<device>` indices
+/// into `get_platforms()` and `Platform::get_devices()`, or to part of a device's name.
+#[derive(Clone)]
+pub struct DeviceSelector {
+    device_type: ll::DeviceType,
+    min_version: Option<(u32, u32)>,
+    extensions: Vec<String>,
+    min_global_mem: u64,
+    name: Option<Regex>,
+    filters: Vec<Arc<dyn Fn(&Device) -> Result<bool> + Send + Sync>>,
+    rank: Option<Arc<dyn Fn(&Device) -> Result<u64> + Send + Sync>>,
+    env_var: Option<String>,
+}
+
+impl fmt::Debug for DeviceSelector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DeviceSelector")
+            .field("device_type", &self.device_type)
+            .field("min_version", &self.min_version)
+            .field("extensions", &self.extensions)
+            .field("min_global_mem", &self.min_global_mem)
+            .field("name", &self.name)
+            .field("filters", &self.filters.len())
+            .field("rank", &self.rank.is_some())
+            .field("env_var", &self.env_var)
+            .finish()
+    }
+}
+
+impl DeviceSelector {
+    pub fn new() -> DeviceSelector {
+        DeviceSelector {
+            device_type: ll::device_type::ALL,
+            min_version: None,
+            extensions: Vec::new(),
+            min_global_mem: 0,
+            name: None,
+            filters: Vec::new(),
+            rank: None,
+            env_var: Some("RASCAL_DEVICE".to_string()),
+        }
+    }
+
+    /// Only consider devices of (one of) the given type(s).
+    pub fn device_type(mut self, device_type: ll::DeviceType) -> DeviceSelector {
+        self.device_type = device_type;
+        self
+    }
+
+    /// Only consider devices supporting at least OpenCL `major.minor`.
+    pub fn min_version(mut self, major: u32, minor: u32) -> DeviceSelector {
+        self.min_version = Some((major, minor));
+        self
+    }
+
+    /// Only consider devices supporting the given extension. Can be given more than once.
+    pub fn require_extension<S: Into<String>>(mut self, name: S) -> DeviceSelector {
+        self.extensions.push(name.into());
+        self
+    }
+
+    /// Only consider devices with at least `bytes` of global memory.
+    pub fn min_global_mem(mut self, bytes: u64) -> DeviceSelector {
+        self.min_global_mem = bytes;
+        self
+    }
+
+    /// Only consider devices whose name matches `regex`.
+    pub fn name_matches(mut self, regex: Regex) -> DeviceSelector {
+        self.name = Some(regex);
+        self
+    }
+
+    /// Only consider devices for which `filter` returns `Ok(true)`. A device it fails on is
+    /// skipped, like one that can't be queried. Can be given more than once.
+    pub fn filter<F>(mut self, filter: F) -> DeviceSelector
+        where F: Fn(&Device) -> Result<bool> + Send + Sync + 'static
+    {
+        self.filters.push(Arc::new(filter));
+        self
+    }
+
+    /// Rank devices by `rank` (highest first), falling back on the usual ranking only to break
+    /// ties. A device it fails on is skipped. Replaces any earlier `rank_by`.
+    pub fn rank_by<F>(mut self, rank: F) -> DeviceSelector
+        where F: Fn(&Device) -> Result<u64> + Send + Sync + 'static
+    {
+        self.rank = Some(Arc::new(rank));
+        self
+    }
+
+    /// Read the override from `var` instead of `RASCAL_DEVICE`.
+    pub fn env_override<S: Into<String>>(mut self, var: S) -> DeviceSelector {
+        self.env_var = Some(var.into());
+        self
+    }
+
+    pub fn no_env_override(mut self) -> DeviceSelector {
+        self.env_var = None;
+        self
+    }
+
+    /// Returns the best-ranked device (or the overridden one).
+    pub fn select(&self) -> Result<(Platform, Device)> {
+        let overridden = self.env_var.as_ref().and_then(|var| env::var(var).ok());
+        if let Some(value) = overridden {
+            return select_override(&value);
+        }
+        match try!(self.candidates()).into_iter().next() {
+            Some(candidate) => Ok(candidate),
+            None => Err(Error::new("clGetDeviceIDs", CLStatus::CL_DEVICE_NOT_FOUND)
+                .with_message("no device meets the selector's requirements")),
+        }
+    }
+
+    /// Returns every device meeting the requirements, best-ranked first. Ignores the
+    /// environment override.
+    pub fn candidates(&self) -> Result<Vec<(Platform, Device)>> {
+        let mut candidates = Vec::new();
+        for platform in try!(get_platforms()) {
+            let devices = try!(ll::get_device_ids(platform.0, self.device_type));
+            for device in devices.into_iter().map(Device) {
+                // a device we can't even query isn't one we want to run on.
+                if let Ok(Some(score)) = self.score(&device) {
+                    candidates.push((score, platform, device));
+                }
+            }
+        }
+        candidates.sort_by(|a, b| b.0.cmp(&a.0));
+        Ok(candidates.into_iter().map(|(_, platform, device)| (platform, device)).collect())
+    }
+
+    /// Ranks `device`, or returns `None` if it doesn't meet the requirements.
+    fn score(&self, device: &Device) -> Result<Option<(u64, u32, u64, u64)>> {
+        if try!(ll::get_device_info(device.0, ll::DeviceInfoBool::Available)) == 0 {
+            return Ok(None);
+        }
+        if let Some((major, minor)) = self.min_version {
+            if !try!(device.cl_version()).at_least(major, minor) {
+                return Ok(None);
+            }
+        }
+        if !self.extensions.is_empty() {
+            let extensions = try!(device.extension_set());
+            if !self.extensions.iter().all(|name| extensions.contains(name)) {
+                return Ok(None);
+            }
+        }
+        let global_mem = try!(device.global_mem_size());
+        if global_mem < self.min_global_mem {
+            return Ok(None);
+        }
+        if let Some(ref regex) = self.name {
+            if !regex.is_match(&try!(device.name())) {
+                return Ok(None);
+            }
+        }
+        for filter in self.filters.iter() {
+            if !try!(filter(device)) {
+                return Ok(None);
+            }
+        }
+        let custom_rank = match self.rank {
+            Some(ref rank) => try!(rank(device)),
+            None => 0,
+        };
+        let device_type = try!(device.device_type());
+        let type_rank = if device_type.contains(ll::device_type::GPU) {
+            3
+        } else if device_type.contains(ll::device_type::ACCELERATOR) {
+            2
+        } else if device_type.contains(ll::device_type::CPU) {
+            1
+        } else {
+            0
+        };
+        let throughput = try!(device.num_compute_units()) as u64
+            * try!(device.max_clock_frequency()) as u64;
+        Ok(Some((custom_rank, type_rank, throughput, global_mem)))
+    }
+}
+
+impl Default for DeviceSelector {
+    fn default() -> DeviceSelector {
+        DeviceSelector::new()
+    }
+}
+
+/// Picks the device named by an override: `<platform>:<device>` indices, or part of a name.
+fn select_override(value: &str) -> Result<(Platform, Device)> {
+    let not_found = || Error::new("clGetDeviceIDs", CLStatus::CL_DEVICE_NOT_FOUND)
+        .with_message(format!("no device matches the override {:?}", value));
+    let platforms = try!(get_platforms());
+    let mut indices = value.splitn(2, ':').map(|idx| idx.trim().parse::<usize>());
+    if let (Some(Ok(platform_idx)), Some(Ok(device_idx))) = (indices.next(), indices.next()) {
+        let platform = match platforms.get(platform_idx) {
+            Some(&platform) => platform,
+            None => return Err(not_found()),
+        };
+        return match try!(platform.get_devices()).get(device_idx) {
+            Some(&device) => Ok((platform, device)),
+            None => Err(not_found()),
+        };
+    }
+    let value = value.to_lowercase();
+    for platform in platforms {
+        for device in try!(platform.get_devices()) {
+            if try!(device.name()).to_lowercase().contains(&value) {
+                return Ok((platform, device));
+            }
+        }
+    }
+    Err(not_found())
+}
+
+/// Serializes bitflags (which don't implement `Serialize`) as their `Debug` form, e.g.
+/// `"READ_ONLY | ..."`.
+#[cfg(feature = "serde")]
+fn serialize_debug<T: fmt::Debug, S: Serializer>(value: &T, serializer: S)
+    -> ::std::result::Result<S::Ok, S::Error>
+{
+    serializer.collect_str(&format_args!("{:?}", value))
+}
+
+/// Everything `Device` can report about itself, gathered by `Device::capabilities`. With the
+/// `serde` feature enabled this implements `Serialize`, e.g. for logging device inventories
+/// as JSON.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct DeviceCapabilities {
+    pub name: String,
+    pub vendor: String,
+    pub vendor_id: u32,
+    pub profile: String,
+    pub device_version: String,
+    pub driver_version: String,
+    pub extensions: String,
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_debug"))]
+    pub device_type: ll::DeviceType,
+
+    pub available: bool,
+    pub compiler_available: bool,
+    pub endian_little: bool,
+    pub error_correction_support: bool,
+    pub image_support: bool,
+
+    pub max_compute_units: u32,
+    /// In MHz.
+    pub max_clock_frequency: u32,
+    pub address_bits: u32,
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_debug"))]
+    pub execution_capabilities: ll::exec_capabilities::ExecCapabilities,
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_debug"))]
+    pub queue_properties: ll::queue_properties::QueueProperties,
+    /// In nanoseconds.
+    pub profiling_timer_resolution: usize,
+
+    // memory sizes are all in bytes.
+    pub global_mem_size: u64,
+    pub global_mem_cache_size: u64,
+    pub global_mem_cacheline_size: u32,
+    pub global_mem_cache_type: ll::DeviceMemCacheType,
+    pub local_mem_size: u64,
+    pub local_mem_type: ll::DeviceLocalMemType,
+    pub max_mem_alloc_size: u64,
+    pub max_constant_buffer_size: u64,
+    pub max_constant_args: u32,
+    /// In bits.
+    pub mem_base_addr_align: u32,
+    pub min_data_type_align_size: u32,
+
+    pub max_work_group_size: usize,
+    pub max_work_item_sizes: Vec<usize>,
+    pub max_parameter_size: usize,
+
+    pub max_read_image_args: u32,
+    pub max_write_image_args: u32,
+    pub max_samplers: u32,
+    pub image2d_max_width: usize,
+    pub image2d_max_height: usize,
+    pub image3d_max_width: usize,
+    pub image3d_max_height: usize,
+    pub image3d_max_depth: usize,
+
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_debug"))]
+    pub single_fp_config: ll::fp_config::FpConfig,
+    /// Empty if the device doesn't support doubles.
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_debug"))]
+    pub double_fp_config: ll::fp_config::FpConfig,
+    /// Empty if the device doesn't support halfs.
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_debug"))]
+    pub half_fp_config: ll::fp_config::FpConfig,
+
+    pub preferred_vector_width_char: u32,
+    pub preferred_vector_width_short: u32,
+    pub preferred_vector_width_int: u32,
+    pub preferred_vector_width_long: u32,
+    pub preferred_vector_width_float: u32,
+    pub preferred_vector_width_double: u32,
+}
+
+/// Values that can be written as OpenCL C literals, for `BuildOptions::define`.
+pub trait ClLiteral {
+    fn to_cl_literal(&self) -> String;
+}
+
+macro_rules! cl_literal {
+    ($($Type:ty => $suffix:expr),*) => {
+        $(
+            impl ClLiteral for $Type {
+                fn to_cl_literal(&self) -> String {
+                    format!("{}{}", self, $suffix)
+                }
+            }
+        )*
+    }
+}
+
+// OpenCL C's long is always 64 bits, unlike C's.
+cl_literal!(i8 => "", i16 => "", i32 => "", i64 => "L", u8 => "u", u16 => "u", u32 => "u",
+    u64 => "uL");
+
+impl ClLiteral for f32 {
+    fn to_cl_literal(&self) -> String {
+        float_literal(*self, "f")
+    }
+}
+
+impl ClLiteral for f64 {
+    fn to_cl_literal(&self) -> String {
+        float_literal(*self, "")
+    }
+}
+
+fn float_literal<T: Into<f64> + fmt::Debug + Copy>(value: T, suffix: &str) -> String {
+    let as_f64: f64 = value.into();
+    if as_f64.is_nan() {
+        "NAN".to_string()
+    } else if as_f64.is_infinite() {
+        (if as_f64 > 0.0 { "INFINITY" } else { "(-INFINITY)" }).to_string()
+    } else {
+        // Debug always includes a '.' or an exponent, so the literal can't read as an integer.
+        format!("{:?}{}", value, suffix)
+    }
+}
+
+/// `1` or `0`, so that the macro also works in `#if`.
+impl ClLiteral for bool {
+    fn to_cl_literal(&self) -> String {
+        (if *self { "1" } else { "0" }).to_string()
+    }
+}
+
+/// Taken verbatim, e.g. a type name. Building with a value containing whitespace fails, since
+/// it would split the option in two.
+impl ClLiteral for str {
+    fn to_cl_literal(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl ClLiteral for String {
+    fn to_cl_literal(&self) -> String {
+        self.clone()
+    }
+}
+
+impl<'a, T: ClLiteral + ?Sized> ClLiteral for &'a T {
+    fn to_cl_literal(&self) -> String {
+        (**self).to_cl_literal()
+    }
+}
+
+/// The options to build a program with. Renders to the option string OpenCL takes through
+/// `Display`, always in the same order for the same options, so the string is fit for
+/// hashing.
+///
+/// Option strings have no portable quoting, so the build functions reject options that can't
+/// be passed through one intact: define names that aren't identifiers, define values
+/// containing whitespace, and include paths containing whitespace or quotes (or that aren't
+/// valid unicode).
+///
+/// ```ignore
+/// let options = BuildOptions::new()
+///     .define("TILE_SIZE", 16u32)
+///     .define_flag("USE_LOCAL")
+///     .include_path("kernels/include")
+///     .cl_std(1, 2)
+///     .fast_relaxed_math();
+/// let program = try!(context.build_program(source, &options));
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BuildOptions {
+    defines: BTreeMap<String, Option<String>>,
+    include_paths: Vec<PathBuf>,
+    cl_std: Option<(u32, u32)>,
+    flags: BTreeSet<&'static str>,
+    extra: Vec<String>,
+}
+
+impl BuildOptions {
+    pub fn new() -> BuildOptions {
+        BuildOptions::default()
+    }
+
+    /// `-D name=value`. Replaces any earlier definition of `name`.
+    pub fn define<N: Into<String>, V: ClLiteral>(mut self, name: N, value: V) -> BuildOptions {
+        self.defines.insert(name.into(), Some(value.to_cl_literal()));
+        self
+    }
+
+    /// `-D name`, defining `name` as `1`.
+    pub fn define_flag<N: Into<String>>(mut self, name: N) -> BuildOptions {
+        self.defines.insert(name.into(), None);
+        self
+    }
+
+    /// `-I path`. Include paths are searched in the order they're given.
+    pub fn include_path<P: Into<PathBuf>>(mut self, path: P) -> BuildOptions {
+        self.include_paths.push(path.into());
+        self
+    }
+
+    /// `-cl-std=CLmajor.minor`, the OpenCL C version to compile against.
+    pub fn cl_std(mut self, major: u32, minor: u32) -> BuildOptions {
+        self.cl_std = Some((major, minor));
+        self
+    }
+
+    /// `-cl-fast-relaxed-math`, which implies all of the other math flags below.
+    pub fn fast_relaxed_math(self) -> BuildOptions {
+        self.flag("-cl-fast-relaxed-math")
+    }
+
+    /// `-cl-mad-enable`: allow `a * b + c` to be computed with reduced accuracy.
+    pub fn mad_enable(self) -> BuildOptions {
+        self.flag("-cl-mad-enable")
+    }
+
+    /// `-cl-no-signed-zeros`.
+    pub fn no_signed_zeros(self) -> BuildOptions {
+        self.flag("-cl-no-signed-zeros")
+    }
+
+    /// `-cl-unsafe-math-optimizations`, which implies `mad_enable` and `no_signed_zeros`.
+    pub fn unsafe_math_optimizations(self) -> BuildOptions {
+        self.flag("-cl-unsafe-math-optimizations")
+    }
+
+    /// `-cl-finite-math-only`: assume no argument or result is NaN or infinite.
+    pub fn finite_math_only(self) -> BuildOptions {
+        self.flag("-cl-finite-math-only")
+    }
+
+    /// `-cl-denorms-are-zero`.
+    pub fn denorms_are_zero(self) -> BuildOptions {
+        self.flag("-cl-denorms-are-zero")
+    }
+
+    /// `-Werror`: fail the build on any warning.
+    pub fn warnings_as_errors(self) -> BuildOptions {
+        self.flag("-Werror")
+    }
+
+    /// Any other options, passed on as given after everything else.
+    pub fn extra<S: Into<String>>(mut self, options: S) -> BuildOptions {
+        self.extra.push(options.into());
+        self
+    }
+
+    fn flag(mut self, flag: &'static str) -> BuildOptions {
+        self.flags.insert(flag);
+        self
+    }
+}
+
+impl BuildOptions {
+    /// The option string, or an error blaming `function` if any option can't be passed on
+    /// intact.
+    fn render(&self, function: &'static str) -> Result<String> {
+        let invalid = |message: String| {
+            Err(Error::new(function, CLStatus::CL_INVALID_BUILD_OPTIONS).with_message(message))
+        };
+        for (name, value) in self.defines.iter() {
+            if !is_identifier(name) {
+                return invalid(format!("define name {:?} isn't an identifier", name));
+            }
+            if let Some(ref value) = *value {
+                if value.is_empty() || value.contains(char::is_whitespace) {
+                    return invalid(format!("value {:?} of define {} is empty or contains \
+                        whitespace", value, name));
+                }
+            }
+        }
+        for path in self.include_paths.iter() {
+            match path.to_str() {
+                Some(path) if !path.is_empty()
+                    && !path.contains(|c: char| c.is_whitespace() || c == '"' || c == '\'') => (),
+                _ => return invalid(format!("include path {:?} is empty or contains \
+                    whitespace or quotes", path)),
+            }
+        }
+        Ok(self.to_string())
+    }
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c == '_' || c.is_ascii_alphabetic() => (),
+        _ => return false,
+    }
+    chars.all(|c| c == '_' || c.is_ascii_alphanumeric())
+}
+
+impl fmt::Display for BuildOptions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut options = Vec::new();
+        for (name, value) in self.defines.iter() {
+            options.push(match *value {
+                Some(ref value) => format!("-D {}={}", name, value),
+                None => format!("-D {}", name),
+            });
+        }
+        for path in self.include_paths.iter() {
+            options.push(format!("-I {}", path.display()));
+        }
+        if let Some((major, minor)) = self.cl_std {
+            options.push(format!("-cl-std=CL{}.{}", major, minor));
+        }
+        for flag in self.flags.iter() {
+            options.push(flag.to_string());
+        }
+        for extra in self.extra.iter().filter(|extra| !extra.is_empty()) {
+            options.push(extra.clone());
+        }
+        write!(f, "{}", options.join(" "))
+    }
+}
+
+impl<'a> From<&'a str> for BuildOptions {
+    fn from(options: &'a str) -> BuildOptions {
+        BuildOptions::new().extra(options)
+    }
+}
+
+impl From<String> for BuildOptions {
+    fn from(options: String) -> BuildOptions {
+        BuildOptions::new().extra(options)
+    }
+}
+
+/// Keeps built program binaries on disk, so that programs only need compiling from source the
+/// first time they're built for a device (or after its driver changes).
+///
+/// Binaries are keyed on the program source, the build options, and the device name and
+/// driver version. The cache is best-effort: if the directory can't be read or written the
+/// program is simply built from source.
+///
+/// Anything else the build reads isn't part of the key; in particular, editing a header under
+/// one of the options' include paths leaves the key unchanged, and the stale binary is loaded.
+/// Pass such inputs (e.g. the header contents) to `build_with_key` to have them hashed too.
+#[derive(Debug, Clone)]
+pub struct ProgramCache {
+    dir: PathBuf,
+}
+
+impl ProgramCache {
+    /// A cache keeping its binaries in `dir`, which is created on first use.
+    pub fn new<P: Into<PathBuf>>(dir: P) -> ProgramCache {
+        ProgramCache { dir: dir.into() }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Like `Context::build_program`, but loads the program from the cache if every device
+    /// of `context` has a binary there, and stores the binaries after building from source
+    /// otherwise. Binaries the driver rejects are rebuilt and replaced.
+    pub fn build(&self, context: &Context, source: &str, options: &BuildOptions)
+        -> Result<Program>
+    {
+        self.build_with_key(context, source, options, &[])
+    }
+
+    /// Like `build`, but also mixes `extra_key` into the cache key, so that a change to any
+    /// of it (such as an included header) means a rebuild.
+    pub fn build_with_key(&self, context: &Context, source: &str, options: &BuildOptions,
+        extra_key: &[&[u8]])
+        -> Result<Program>
+    {
+        let rendered = try!(options.render("clBuildProgram"));
+        let devices = try!(context.devices());
+        let mut paths = Vec::with_capacity(devices.len());
+        for device in devices.iter() {
+            let key = try!(cache_key(device, source, &rendered, extra_key));
+            paths.push(self.dir.join(format!("{:016x}.bin", key)));
+        }
+        if let Some(program) = self.load(context, &devices, &paths, &rendered) {
+            return Ok(program);
+        }
+        let program = try!(context.build_program(source, options));
+        self.store(&program, &devices, &paths);
+        Ok(program)
+    }
+
+    fn load(&self, context: &Context, devices: &[Device], paths: &[PathBuf], options: &str)
+        -> Option<Program>
+    {
+        let mut binaries = Vec::with_capacity(paths.len());
+        for path in paths.iter() {
+            match fs::read(path) {
+                Ok(binary) => binaries.push(binary),
+                Err(_) => return None,
+            }
+        }
+        let ids: Vec<_> = devices.iter().map(|d| d.0).collect();
+        let binaries: Vec<_> = binaries.iter().map(|b| &b[..]).collect();
+        let program = match ll::create_program_with_binary(&context.0, &ids, &binaries) {
+            Ok(program) => program,
+            Err(_) => return None,
+        };
+        match ll::build_program(&program, &ids, options) {
+            Ok(()) => Some(Program(program)),
+            Err(_) => None,
+        }
+    }
+
+    fn store(&self, program: &Program, devices: &[Device], paths: &[PathBuf]) {
+        let binaries = match ll::get_program_binaries(&program.0) {
+            Ok(binaries) => binaries,
+            Err(_) => return,
+        };
+        if fs::create_dir_all(&self.dir).is_err() {
+            return;
+        }
+        for (device, path) in devices.iter().zip(paths) {
+            let binary = binaries.iter().find(|&&(id, _)| id == device.0);
+            if let Some(&(_, ref binary)) = binary {
+                if binary.is_empty() {
+                    continue;
+                }
+                // write then rename, so that a concurrent build never reads half a binary.
+                let tmp = path.with_extension(format!("tmp{}-{}", process::id(),
+                    TMP_COUNTER.fetch_add(1, atomic::Ordering::Relaxed)));
+                if fs::write(&tmp, binary).is_err() || fs::rename(&tmp, path).is_err() {
+                    let _ = fs::remove_file(&tmp);
+                }
+            }
+        }
+    }
+}
+
+/// Tells apart the temporary files of writers in the same process.
+static TMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+fn cache_key(device: &Device, source: &str, options: &str, extra_key: &[&[u8]])
+    -> Result<u64>
+{
+    let name = try!(device.name());
+    let driver_version = try!(device.driver_version());
+    let parts = [source.as_bytes(), options.as_bytes(), name.as_bytes(),
+        driver_version.as_bytes()];
+    let mut hash = Fnv1a::new();
+    for part in parts.iter().chain(extra_key) {
+        // length-prefixed, which keeps ("ab", "c") and ("a", "bc") apart.
+        hash.write(&(part.len() as u64).to_le_bytes());
+        hash.write(part);
+    }
+    Ok(hash.finish())
+}
+
+/// 64-bit FNV-1a. The cache needs a hash that's stable across builds and Rust versions, which
+/// `DefaultHasher` doesn't promise.
+struct Fnv1a(u64);
+
+impl Fnv1a {
+    fn new() -> Fnv1a {
+        Fnv1a(0xcbf29ce484222325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BuildOptions, ClVersion};
+
+    #[test]
+    fn parses_platform_versions() {
+        let version = ClVersion::parse("OpenCL 1.2").unwrap();
+        assert_eq!((version.major, version.minor), (1, 2));
+        assert_eq!(version.vendor_info, "");
+    }
+
+    #[test]
+    fn parses_opencl_c_versions() {
+        let version = ClVersion::parse("OpenCL C 2.0 foo").unwrap();
+        assert_eq!((version.major, version.minor), (2, 0));
+        assert_eq!(version.vendor_info, "foo");
+    }
+
+    #[test]
+    fn keeps_vendor_info_out_of_comparisons() {
+        let version = ClVersion::parse("OpenCL 1.2 CUDA 11.4.112").unwrap();
+        assert_eq!(version.vendor_info, "CUDA 11.4.112");
+        assert_eq!(version, ClVersion::new(1, 2));
+        assert!(version < ClVersion::new(2, 0));
+        assert!(ClVersion::new(1, 10) > ClVersion::new(1, 2));
+        assert!(version.at_least(1, 1));
+        assert!(!version.at_least(1, 3));
     }
 
-    pub fn profile(&self) -> String {
-        ll::get_device_info(self.0, ll::DeviceInfoString::Profile).unwrap()
+    #[test]
+    fn rejects_malformed_versions() {
+        assert_eq!(ClVersion::parse("OpenCL"), None);
+        assert_eq!(ClVersion::parse("OpenCL one.two"), None);
+        assert_eq!(ClVersion::parse("OpenCL 1"), None);
+        assert_eq!(ClVersion::parse("1.2"), None);
     }
 
-    pub fn vendor(&self) -> String {
-        ll::get_device_info(self.0, ll::DeviceInfoString::Vendor).unwrap()
+    #[test]
+    fn renders_build_options_in_a_fixed_order() {
+        let a = BuildOptions::new()
+            .extra("-g")
+            .warnings_as_errors()
+            .define("B", 2i32)
+            .fast_relaxed_math()
+            .include_path("inc")
+            .define_flag("A")
+            .cl_std(1, 2);
+        let b = BuildOptions::new()
+            .cl_std(1, 2)
+            .define_flag("A")
+            .include_path("inc")
+            .define("B", 2i32)
+            .fast_relaxed_math()
+            .warnings_as_errors()
+            .extra("-g");
+        let expected = "-D A -D B=2 -I inc -cl-std=CL1.2 -Werror -cl-fast-relaxed-math -g";
+        assert_eq!(a.to_string(), expected);
+        assert_eq!(b.to_string(), expected);
+        assert_eq!(a.render("clBuildProgram").unwrap(), expected);
     }
 
-    pub fn device_version(&self) -> String {
-        ll::get_device_info(self.0, ll::DeviceInfoString::DeviceVersion).unwrap()
+    #[test]
+    fn renders_float_literals() {
+        let options = BuildOptions::new()
+            .define("ONE", 1.0f32)
+            .define("HALF", 0.5f64)
+            .define("NOT_A_NUMBER", ::std::f32::NAN)
+            .define("NEG_INF", ::std::f32::NEG_INFINITY)
+            .define("POS_INF", ::std::f64::INFINITY);
+        assert_eq!(options.to_string(), "-D HALF=0.5 -D NEG_INF=(-INFINITY) \
+            -D NOT_A_NUMBER=NAN -D ONE=1.0f -D POS_INF=INFINITY");
     }
 
-    pub fn driver_version(&self) -> String {
-        ll::get_device_info(self.0, ll::DeviceInfoString::DriverVersion).unwrap()
+    #[test]
+    fn renders_integer_suffixes() {
+        let options = BuildOptions::new()
+            .define("A", -3i32)
+            .define("B", 3u32)
+            .define("C", 3i64)
+            .define("D", 3u64)
+            .define("E", true)
+            .define("F", "float4");
+        assert_eq!(options.to_string(), "-D A=-3 -D B=3u -D C=3L -D D=3uL -D E=1 -D F=float4");
     }
 
-    pub fn extensions(&self) -> String {
-        ll::get_device_info(self.0, ll::DeviceInfoString::Extensions).unwrap()
+    #[test]
+    fn dedups_flags_and_defines() {
+        let options = BuildOptions::new()
+            .mad_enable()
+            .mad_enable()
+            .define("N", 1u32)
+            .define("N", 2u32);
+        assert_eq!(options.to_string(), "-D N=2u -cl-mad-enable");
     }
 
-    pub fn device_type(&self) -> ll::DeviceType {
-        ll::get_device_info(self.0, ll::DeviceInfoDeviceType).unwrap()
+    #[test]
+    fn treats_strings_as_extras() {
+        assert_eq!(BuildOptions::from("-w").to_string(), "-w");
+        assert_eq!(BuildOptions::from("").to_string(), "");
     }
 
-    pub fn num_compute_units(&self) -> usize {
-        ll::get_device_info(self.0, ll::DeviceInfoClUint::MaxComputeUnits).unwrap() as usize
+    #[test]
+    fn rejects_options_that_cant_be_passed_intact() {
+        let render = |options: BuildOptions| options.render("clBuildProgram").is_err();
+        assert!(render(BuildOptions::new().define("T", "unsigned int")));
+        assert!(render(BuildOptions::new().define("T", "")));
+        assert!(render(BuildOptions::new().define("1X", 1i32)));
+        assert!(render(BuildOptions::new().define_flag("A B")));
+        assert!(render(BuildOptions::new().include_path("a\"b")));
+        assert!(render(BuildOptions::new().include_path("with space")));
+        assert!(!render(BuildOptions::new().include_path("kernels/include")));
     }
 }
@@ -3,8 +3,14 @@ extern crate bitflags;
 extern crate opencl;
 extern crate num;
 extern crate libc;
+extern crate regex;
+#[cfg(feature = "serde")]
+extern crate serde;
 
 pub mod ll;
 pub mod hl;
+pub mod error;
 
-pub type Result<A> = ::std::result::Result<A, opencl::cl::CLStatus>;
+pub use error::{Error, Status};
+
+pub type Result<A> = ::std::result::Result<A, Error>;
@@ -1,23 +1,109 @@
 use opencl::cl;
+use opencl::cl::CLStatus;
 use libc;
-use std::ffi::CStr;
+use std::ffi::{CStr, CString};
 use std::ptr;
 use std::mem;
 use std::iter::repeat;
-use num;
-use super::Result;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::slice;
+use std::time::Duration;
+use std::panic;
+use std::future::{Future, IntoFuture};
+use std::pin::Pin;
+use std::any::TypeId;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{self, Poll, Waker};
+use std::sync::mpsc;
+use std::io::{self, Write};
+use super::{Error, Result, Status};
+#[cfg(feature = "serde")]
+use serde::Serialize;
 
-fn check_status(status_int: cl::cl_int) -> Result<()> {
-    let status = num::FromPrimitive::from_i32(status_int);
-    match status {
-        Some(cl::CLStatus::CL_SUCCESS) => Ok(()),
-        Some(other) => Err(other),
-        None => panic!("Rascal: Tried to check invalid opencl status! (Value was {})", status_int)
+fn check_status(function: &'static str, status_int: cl::cl_int) -> Result<()> {
+    if status_int == cl::CL_SUCCESS {
+        Ok(())
+    } else {
+        Err(Error::new(function, Status::from_code(status_int)))
     }
 }
 
 pub use self::device_type::DeviceType;
 
+/// Entry points we declare ourselves rather than going through `opencl::cl::ll`, either because
+/// it lacks them, or because the callback they take has to be nullable (passing one changes
+/// the call's behaviour).
+mod ffi {
+    use opencl::cl;
+    use libc;
+
+    extern "C" {
+        pub fn clBuildProgram(program: cl::cl_program, num_devices: cl::cl_uint,
+            device_list: *const cl::cl_device_id, options: *const libc::c_char,
+            pfn_notify: Option<extern "C" fn(cl::cl_program, *mut libc::c_void)>,
+            user_data: *mut libc::c_void) -> cl::cl_int;
+
+        pub fn clEnqueueFillBuffer(command_queue: cl::cl_command_queue, buffer: cl::cl_mem,
+            pattern: *const libc::c_void, pattern_size: libc::size_t, offset: libc::size_t,
+            size: libc::size_t, num_events_in_wait_list: cl::cl_uint,
+            event_wait_list: *const cl::cl_event, event: *mut cl::cl_event) -> cl::cl_int;
+
+        pub fn clCreateUserEvent(context: cl::cl_context, errcode_ret: *mut cl::cl_int)
+            -> cl::cl_event;
+
+        pub fn clSetUserEventStatus(event: cl::cl_event, execution_status: cl::cl_int)
+            -> cl::cl_int;
+
+        pub fn clSetEventCallback(event: cl::cl_event, command_exec_callback_type: cl::cl_int,
+            pfn_notify: Option<extern "C" fn(cl::cl_event, cl::cl_int, *mut libc::c_void)>,
+            user_data: *mut libc::c_void) -> cl::cl_int;
+
+        pub fn clCreateContextFromType(properties: *const cl::cl_context_properties,
+            device_type: cl::cl_device_type,
+            pfn_notify: Option<extern "C" fn(*const libc::c_char, *const libc::c_void,
+                libc::size_t, *mut libc::c_void)>,
+            user_data: *mut libc::c_void, errcode_ret: *mut cl::cl_int) -> cl::cl_context;
+
+        pub fn clCreateProgramWithBinary(context: cl::cl_context, num_devices: cl::cl_uint,
+            device_list: *const cl::cl_device_id, lengths: *const libc::size_t,
+            binaries: *const *const libc::c_uchar, binary_status: *mut cl::cl_int,
+            errcode_ret: *mut cl::cl_int) -> cl::cl_program;
+
+        pub fn clCompileProgram(program: cl::cl_program, num_devices: cl::cl_uint,
+            device_list: *const cl::cl_device_id, options: *const libc::c_char,
+            num_input_headers: cl::cl_uint, input_headers: *const cl::cl_program,
+            header_include_names: *const *const libc::c_char,
+            pfn_notify: Option<extern "C" fn(cl::cl_program, *mut libc::c_void)>,
+            user_data: *mut libc::c_void) -> cl::cl_int;
+
+        pub fn clLinkProgram(context: cl::cl_context, num_devices: cl::cl_uint,
+            device_list: *const cl::cl_device_id, options: *const libc::c_char,
+            num_input_programs: cl::cl_uint, input_programs: *const cl::cl_program,
+            pfn_notify: Option<extern "C" fn(cl::cl_program, *mut libc::c_void)>,
+            user_data: *mut libc::c_void, errcode_ret: *mut cl::cl_int) -> cl::cl_program;
+    }
+}
+
+/// Types that can be copied to and from device memory byte-for-byte: every bit pattern of
+/// the right size must be a valid value.
+pub unsafe trait Pod: Copy {}
+
+macro_rules! pod {
+    ($($Type:ty),*) => {
+        $(
+            unsafe impl Pod for $Type {}
+            unsafe impl Pod for [$Type; 2] {}
+            unsafe impl Pod for [$Type; 3] {}
+            unsafe impl Pod for [$Type; 4] {}
+            unsafe impl Pod for [$Type; 8] {}
+            unsafe impl Pod for [$Type; 16] {}
+        )*
+    }
+}
+
+pod!(i8, u8, i16, u16, i32, u32, i64, u64, isize, usize, f32, f64);
+
 macro_rules! newtype_to_from_raw {
     ($($(#[$Meta:meta])* pub struct $Name:ident($Type:ty));*;) => {
         $(
@@ -67,23 +153,29 @@ newtype_to_from_raw! {
     pub struct Sampler(cl::cl_sampler);
 }
 
-/// Turns a vector of `u8`s into a Rust string.
-/// Assumes that the vector had a string `s` written to it, with `strlen(s) == buf.len() - 1`
-/// (i.e. the last entry in the vector should be the terminating null character).
+// OpenCL event objects are safe to use from any thread, and our only state is the handle.
+unsafe impl Send for Event {}
+unsafe impl Sync for Event {}
+
+// the futures built on events have to be Send to be held across an `.await` in a task on a
+// work-stealing executor; this stops the build if they ever aren't.
+#[allow(dead_code)]
+fn assert_event_futures_send() {
+    fn is_send<T: Send>() {}
+    is_send::<EventFuture>();
+    is_send::<super::hl::PendingRead<f32>>();
+}
+
+/// Turns a vector of `u8`s that had a C string written to it into a Rust string, stopping at
+/// the first null character (if any). An empty buffer gives an empty string, and invalid
+/// utf8 is replaced rather than failing the whole query over a driver's odd device name.
 fn string_from_cstring_buf(mut buf: Vec<u8>) -> String {
-    let last_char = buf.pop();
-    match last_char {
-        Some(0u8) => {
-            match String::from_utf8(buf) {
-                Ok(string) => string,
-                Err(err) => panic!(
-                    "Rascal: Invalid utf8 sequence in buffer! (Error: {:?})", err),
-            }
-        }
-        Some(other) => panic!(
-            "Rascal: Last char in buffer wasn't null! (Expected 0, found {})", other),
-        None => panic!(
-            "Rascal: Tried to turn empty buffer into string! (Expected null character)"),
+    if let Some(end) = buf.iter().position(|&c| c == 0) {
+        buf.truncate(end);
+    }
+    match String::from_utf8(buf) {
+        Ok(string) => string,
+        Err(err) => String::from_utf8_lossy(err.as_bytes()).into_owned(),
     }
 }
 
@@ -134,18 +226,175 @@ pub mod queue_properties {
     }
 }
 
-// TODO, although none of these are very useful
+pub mod fp_config {
+    use opencl::cl;
+    bitflags! {
+        flags FpConfig: cl::cl_device_fp_config {
+            const DENORM = cl::CL_FP_DENORM,
+            const INF_NAN = cl::CL_FP_INF_NAN,
+            const ROUND_TO_NEAREST = cl::CL_FP_ROUND_TO_NEAREST,
+            const ROUND_TO_ZERO = cl::CL_FP_ROUND_TO_ZERO,
+            const ROUND_TO_INF = cl::CL_FP_ROUND_TO_INF,
+            const FMA = cl::CL_FP_FMA,
+            const SOFT_FLOAT = cl::CL_FP_SOFT_FLOAT,
+            const CORRECTLY_ROUNDED_DIVIDE_SQRT = cl::CL_FP_CORRECTLY_ROUNDED_DIVIDE_SQRT,
+        }
+    }
+}
+
+pub mod exec_capabilities {
+    use opencl::cl;
+    bitflags! {
+        flags ExecCapabilities: cl::cl_device_exec_capabilities {
+            const KERNEL = cl::CL_EXEC_KERNEL,
+            const NATIVE_KERNEL = cl::CL_EXEC_NATIVE_KERNEL,
+        }
+    }
+}
+
 pub trait ContextInfo {
     type Info;
     fn get_context_info(self, context: &Context) -> Result<Self::Info>;
 }
 
-// TODO
+#[repr(u32)]
+#[derive(Debug, Copy, Clone)]
+pub enum ContextInfoClUint {
+    ReferenceCount = cl::CL_CONTEXT_REFERENCE_COUNT,
+    NumDevices = cl::CL_CONTEXT_NUM_DEVICES,
+}
+
+impl ContextInfo for ContextInfoClUint {
+    type Info = cl::cl_uint;
+    fn get_context_info(self, context: &Context) -> Result<cl::cl_uint> {
+        unsafe {
+            let mut ret = 0;
+            let res = cl::ll::clGetContextInfo(
+                context.0, self as cl::cl_context_info,
+                mem::size_of::<cl::cl_uint>() as libc::size_t,
+                &mut ret as *mut _ as *mut _, ptr::null_mut());
+            try!(check_status("clGetContextInfo", res));
+            Ok(ret)
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct ContextInfoDevices;
+impl ContextInfo for ContextInfoDevices {
+    type Info = Vec<DeviceId>;
+    fn get_context_info(self, context: &Context) -> Result<Vec<DeviceId>> {
+        unsafe {
+            let mut size = 0;
+            let res = cl::ll::clGetContextInfo(
+                context.0, cl::CL_CONTEXT_DEVICES, 0, ptr::null_mut(), &mut size);
+            try!(check_status("clGetContextInfo", res));
+            let num_devices = size as usize / mem::size_of::<cl::cl_device_id>();
+            let mut ids: Vec<_> = repeat(0 as *mut _).take(num_devices).collect();
+            let res = cl::ll::clGetContextInfo(
+                context.0, cl::CL_CONTEXT_DEVICES,
+                (ids.len() * mem::size_of::<cl::cl_device_id>()) as libc::size_t,
+                ids.as_mut_ptr() as *mut _, ptr::null_mut());
+            try!(check_status("clGetContextInfo", res));
+            Ok(ids.iter().map(|ptr| DeviceId(*ptr)).collect())
+        }
+    }
+}
+
+/// The properties the context was created with.
+#[derive(Debug, Copy, Clone)]
+pub struct ContextInfoProperties;
+impl ContextInfo for ContextInfoProperties {
+    type Info = ContextProperties;
+    fn get_context_info(self, context: &Context) -> Result<ContextProperties> {
+        unsafe {
+            let mut size = 0;
+            let res = cl::ll::clGetContextInfo(
+                context.0, cl::CL_CONTEXT_PROPERTIES, 0, ptr::null_mut(), &mut size);
+            try!(check_status("clGetContextInfo", res));
+            let len = size as usize / mem::size_of::<cl::cl_context_properties>();
+            let mut properties: Vec<cl::cl_context_properties> = repeat(0).take(len).collect();
+            let res = cl::ll::clGetContextInfo(
+                context.0, cl::CL_CONTEXT_PROPERTIES,
+                (properties.len() * mem::size_of::<cl::cl_context_properties>())
+                    as libc::size_t,
+                properties.as_mut_ptr() as *mut _, ptr::null_mut());
+            try!(check_status("clGetContextInfo", res));
+            Ok(ContextProperties::from_raw(&properties[..]))
+        }
+    }
+}
+
 pub trait CommandQueueInfo {
     type Info;
     fn get_command_queue_info(self, queue: &CommandQueue) -> Result<Self::Info>;
 }
 
+/// Reads a fixed-size command queue info value.
+unsafe fn get_command_queue_info_value<T: Copy>(queue: &CommandQueue,
+    info: cl::cl_command_queue_info)
+    -> Result<T>
+{
+    let mut ret: T = mem::zeroed();
+    let res = cl::ll::clGetCommandQueueInfo(
+        queue.0, info, mem::size_of::<T>() as libc::size_t,
+        &mut ret as *mut _ as *mut _, ptr::null_mut());
+    try!(check_status("clGetCommandQueueInfo", res));
+    Ok(ret)
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct CommandQueueInfoContext;
+impl CommandQueueInfo for CommandQueueInfoContext {
+    type Info = Context;
+    fn get_command_queue_info(self, queue: &CommandQueue) -> Result<Context> {
+        unsafe {
+            let context: cl::cl_context =
+                try!(get_command_queue_info_value(queue, cl::CL_QUEUE_CONTEXT));
+            // the queue's reference isn't ours to release, so take one of our own.
+            try!(check_status("clRetainContext", cl::ll::clRetainContext(context)));
+            Ok(Context(context))
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct CommandQueueInfoDevice;
+impl CommandQueueInfo for CommandQueueInfoDevice {
+    type Info = DeviceId;
+    fn get_command_queue_info(self, queue: &CommandQueue) -> Result<DeviceId> {
+        unsafe {
+            get_command_queue_info_value(queue, cl::CL_QUEUE_DEVICE).map(DeviceId)
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct CommandQueueInfoReferenceCount;
+impl CommandQueueInfo for CommandQueueInfoReferenceCount {
+    type Info = cl::cl_uint;
+    fn get_command_queue_info(self, queue: &CommandQueue) -> Result<cl::cl_uint> {
+        unsafe {
+            get_command_queue_info_value(queue, cl::CL_QUEUE_REFERENCE_COUNT)
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct CommandQueueInfoProperties;
+impl CommandQueueInfo for CommandQueueInfoProperties {
+    type Info = queue_properties::QueueProperties;
+    fn get_command_queue_info(self, queue: &CommandQueue)
+        -> Result<queue_properties::QueueProperties>
+    {
+        unsafe {
+            let properties: cl::cl_command_queue_properties =
+                try!(get_command_queue_info_value(queue, cl::CL_QUEUE_PROPERTIES));
+            Ok(queue_properties::QueueProperties::from_bits_truncate(properties))
+        }
+    }
+}
+
 #[repr(u32)]
 #[derive(Debug, Copy, Clone)]
 pub enum PlatformInfo {
@@ -180,7 +429,7 @@ impl DeviceInfo for DeviceInfoBool {
                 device.0, self as cl::cl_device_info,
                 mem::size_of::<cl::cl_bool>() as libc::size_t,
                 &mut ret as *mut _ as *mut _, ptr::null_mut());
-            try!(check_status(res));
+            try!(check_status("clGetDeviceInfo", res));
             Ok(ret)
         }
     }
@@ -204,12 +453,12 @@ impl DeviceInfo for DeviceInfoString {
             let mut str_len = 0;
             let res = cl::ll::clGetDeviceInfo(
                 device.0, self as cl::cl_device_info, 0, ptr::null_mut(), &mut str_len);
-            try!(check_status(res));
+            try!(check_status("clGetDeviceInfo", res));
             let mut bytes: Vec<_> = repeat(0).take(str_len as usize).collect();
             let res = cl::ll::clGetDeviceInfo(
                 device.0, self as cl::cl_device_info, bytes.len() as libc::size_t,
                 bytes.as_mut_ptr() as *mut _ as *mut _, ptr::null_mut());
-            try!(check_status(res));
+            try!(check_status("clGetDeviceInfo", res));
             Ok(string_from_cstring_buf(bytes))
         }
     }
@@ -228,6 +477,8 @@ pub enum DeviceInfoClUint {
     MemBaseAddrAlign = cl::CL_DEVICE_MEM_BASE_ADDR_ALIGN,
     MinDataTypeAlignSize = cl::CL_DEVICE_MIN_DATA_TYPE_ALIGN_SIZE,
     VendorId = cl::CL_DEVICE_VENDOR_ID,
+    AddressBits = cl::CL_DEVICE_ADDRESS_BITS,
+    GlobalMemCachelineSize = cl::CL_DEVICE_GLOBAL_MEM_CACHELINE_SIZE,
     PreferredVectorWidthChar = cl::CL_DEVICE_PREFERRED_VECTOR_WIDTH_CHAR,
     PreferredVectorWidthShort = cl::CL_DEVICE_PREFERRED_VECTOR_WIDTH_SHORT,
     PreferredVectorWidthInt = cl::CL_DEVICE_PREFERRED_VECTOR_WIDTH_INT,
@@ -245,7 +496,7 @@ impl DeviceInfo for DeviceInfoClUint {
                 device.0, self as cl::cl_device_info,
                 mem::size_of::<cl::cl_uint>() as libc::size_t,
                 &mut ret as *mut _ as *mut _, ptr::null_mut());
-            try!(check_status(res));
+            try!(check_status("clGetDeviceInfo", res));
             Ok(ret)
         }
     }
@@ -262,233 +513,2249 @@ impl DeviceInfo for DeviceInfoDeviceType {
                 device.0, cl::CL_DEVICE_TYPE,
                 mem::size_of::<cl::cl_device_type>() as libc::size_t,
                 &mut device_type as *mut _ as *mut _, ptr::null_mut());
-            try!(check_status(res));
-            match DeviceType::from_bits(device_type) {
-                Some(device_type) => Ok(device_type),
-                None => panic!("Rascal: Got invalid device type {}!", device_type),
-            }
+            try!(check_status("clGetDeviceInfo", res));
+            // e.g. CL_DEVICE_TYPE_CUSTOM (OpenCL 1.2), which we don't have a flag for.
+            Ok(DeviceType::from_bits_truncate(device_type))
         }
     }
 }
 
-pub fn get_platform_ids() -> Result<Vec<PlatformId>> {
-    unsafe {
-        let mut num_platforms = 0;
-        let res = cl::ll::clGetPlatformIDs(0, ptr::null_mut(), &mut num_platforms);
-        try!(check_status(res));
-        let mut ids: Vec<_> = repeat(0 as *mut _).take(num_platforms as usize).collect();
-        let res = cl::ll::clGetPlatformIDs(
-            ids.len() as cl::cl_uint, ids.as_mut_ptr(), ptr::null_mut());
-        try!(check_status(res));
-        Ok(ids.iter().map(|ptr| PlatformId(*ptr)).collect())
-    }
+/// Reads a fixed-size device info value.
+unsafe fn get_device_info_value<T: Copy>(device: DeviceId, info: cl::cl_device_info)
+    -> Result<T>
+{
+    let mut ret: T = mem::zeroed();
+    let res = cl::ll::clGetDeviceInfo(
+        device.0, info, mem::size_of::<T>() as libc::size_t,
+        &mut ret as *mut _ as *mut _, ptr::null_mut());
+    try!(check_status("clGetDeviceInfo", res));
+    Ok(ret)
 }
 
-pub fn get_platform_info(platform: PlatformId, info: PlatformInfo) -> Result<String> {
-    unsafe {
-        let mut info_size = 0;
-        let res = cl::ll::clGetPlatformInfo(
-            platform.0, info as cl::cl_platform_info, 0, ptr::null_mut(), &mut info_size);
-        try!(check_status(res));
-        let mut bytes: Vec<_> = repeat(0).take(info_size as usize).collect();
-        let res = cl::ll::clGetPlatformInfo(
-            platform.0, info as cl::cl_platform_info, bytes.len() as libc::size_t,
-            bytes.as_mut_ptr() as *mut _, ptr::null_mut());
-        try!(check_status(res));
-        Ok(string_from_cstring_buf(bytes))
-    }
+#[repr(u32)]
+#[derive(Debug, Copy, Clone)]
+pub enum DeviceInfoClUlong {
+    GlobalMemSize = cl::CL_DEVICE_GLOBAL_MEM_SIZE,
+    GlobalMemCacheSize = cl::CL_DEVICE_GLOBAL_MEM_CACHE_SIZE,
+    LocalMemSize = cl::CL_DEVICE_LOCAL_MEM_SIZE,
+    MaxMemAllocSize = cl::CL_DEVICE_MAX_MEM_ALLOC_SIZE,
+    MaxConstantBufferSize = cl::CL_DEVICE_MAX_CONSTANT_BUFFER_SIZE,
 }
 
-pub fn get_device_ids(platform: PlatformId, device_type: DeviceType)
-    -> Result<Vec<DeviceId>>
-{
-    unsafe {
-        let mut num_devices = 0;
-        let res = cl::ll::clGetDeviceIDs(
-            platform.0, device_type.bits(), 0, ptr::null_mut(), &mut num_devices);
-        try!(check_status(res));
-        let mut ids: Vec<_> = repeat(0 as *mut _).take(num_devices as usize).collect();
-        let res = cl::ll::clGetDeviceIDs(
-            platform.0, device_type.bits(), ids.len() as cl::cl_uint, ids.as_mut_ptr(),
-            ptr::null_mut());
-        try!(check_status(res));
-        Ok(ids.iter().map(|ptr| DeviceId(*ptr)).collect())
+impl DeviceInfo for DeviceInfoClUlong {
+    type Info = cl::cl_ulong;
+    fn get_device_info(self, device: DeviceId) -> Result<cl::cl_ulong> {
+        unsafe { get_device_info_value(device, self as cl::cl_device_info) }
     }
 }
 
-pub fn get_device_info<T: DeviceInfo>(device: DeviceId, info: T) -> Result<T::Info> {
-    info.get_device_info(device)
+#[repr(u32)]
+#[derive(Debug, Copy, Clone)]
+pub enum DeviceInfoSize {
+    MaxWorkGroupSize = cl::CL_DEVICE_MAX_WORK_GROUP_SIZE,
+    MaxParameterSize = cl::CL_DEVICE_MAX_PARAMETER_SIZE,
+    Image2dMaxWidth = cl::CL_DEVICE_IMAGE2D_MAX_WIDTH,
+    Image2dMaxHeight = cl::CL_DEVICE_IMAGE2D_MAX_HEIGHT,
+    Image3dMaxWidth = cl::CL_DEVICE_IMAGE3D_MAX_WIDTH,
+    Image3dMaxHeight = cl::CL_DEVICE_IMAGE3D_MAX_HEIGHT,
+    Image3dMaxDepth = cl::CL_DEVICE_IMAGE3D_MAX_DEPTH,
+    /// In nanoseconds.
+    ProfilingTimerResolution = cl::CL_DEVICE_PROFILING_TIMER_RESOLUTION,
 }
 
-extern "C" fn dummy_context_handler(errinfo: *const libc::c_char,
-    private_info: *const libc::c_void, cb: libc::size_t, user_data: *mut libc::c_void)
-{
-    unsafe {
-        let _ = private_info;
-        let _ = cb;
-        let _ = user_data;
-        let errinfo = CStr::from_ptr(errinfo).to_str();
-        match errinfo {
-            Ok(errinfo) => panic!("Rascal: Got error from OpenCL context! (Error: {})", errinfo),
-            Err(err) => panic!(
-                "Rascal: Got error from OpenCL context! (Error string is invalid: {:?})", err),
+impl DeviceInfo for DeviceInfoSize {
+    type Info = usize;
+    fn get_device_info(self, device: DeviceId) -> Result<usize> {
+        unsafe {
+            get_device_info_value::<libc::size_t>(device, self as cl::cl_device_info)
+                .map(|size| size as usize)
         }
     }
 }
 
-pub fn create_context(platform: PlatformId, devices: &[DeviceId]) -> Result<Context> {
-    unsafe {
-        let mut err = 0;
-        // disgusting and cheaty.
-        let props = [
-            cl::CL_CONTEXT_PLATFORM as usize as cl::cl_context_properties,
-            platform.0 as usize as cl::cl_context_properties,
-            0 as cl::cl_context_properties];
-        let ids: Vec<_> = devices.iter().map(|d| d.0).collect();
-        let context = cl::ll::clCreateContext(
-            props.as_ptr(), ids.len() as cl::cl_uint, ids[..].as_ptr(),
-            dummy_context_handler, ptr::null_mut(),
-            &mut err as *mut _);
-        try!(check_status(err));
-        Ok(Context(context))
+/// The maximum number of work-items in each dimension of a work-group.
+#[derive(Debug, Copy, Clone)]
+pub struct DeviceInfoMaxWorkItemSizes;
+impl DeviceInfo for DeviceInfoMaxWorkItemSizes {
+    type Info = Vec<usize>;
+    fn get_device_info(self, device: DeviceId) -> Result<Vec<usize>> {
+        unsafe {
+            let dims = try!(DeviceInfoClUint::MaxWorkItemDimensions.get_device_info(device));
+            let mut sizes: Vec<libc::size_t> = repeat(0).take(dims as usize).collect();
+            let res = cl::ll::clGetDeviceInfo(
+                device.0, cl::CL_DEVICE_MAX_WORK_ITEM_SIZES,
+                (sizes.len() * mem::size_of::<libc::size_t>()) as libc::size_t,
+                sizes.as_mut_ptr() as *mut _, ptr::null_mut());
+            try!(check_status("clGetDeviceInfo", res));
+            Ok(sizes.into_iter().map(|size| size as usize).collect())
+        }
     }
 }
 
+#[repr(u32)]
 #[derive(Debug, Copy, Clone)]
-pub enum MemProt {
-    ReadWrite,
-    ReadOnly,
-    WriteOnly,
+pub enum DeviceInfoFpConfig {
+    Single = cl::CL_DEVICE_SINGLE_FP_CONFIG,
+    Double = cl::CL_DEVICE_DOUBLE_FP_CONFIG,
+    Half = cl::CL_DEVICE_HALF_FP_CONFIG,
 }
 
-impl MemProt {
-    pub fn to_mem_flags(self) -> mem_flags::MemFlags {
-        match self {
-            MemProt::ReadWrite => mem_flags::READ_WRITE,
-            MemProt::ReadOnly => mem_flags::READ_ONLY,
-            MemProt::WriteOnly => mem_flags::WRITE_ONLY,
+impl DeviceInfo for DeviceInfoFpConfig {
+    type Info = fp_config::FpConfig;
+    fn get_device_info(self, device: DeviceId) -> Result<fp_config::FpConfig> {
+        unsafe {
+            let config: cl::cl_device_fp_config =
+                try!(get_device_info_value(device, self as cl::cl_device_info));
+            // newer versions of the spec add flags we don't know about.
+            Ok(fp_config::FpConfig::from_bits_truncate(config))
         }
     }
 }
 
-// TODO handle the other mem creation cases
-/// Creates an entirely device-backed buffer.
-pub fn create_mem_device_buffer(context: &Context, permissions: MemProt, size: usize)
-    -> Result<Mem>
-{
-    unsafe {
-        let mut err = 0;
-        let mem = cl::ll::clCreateBuffer(
-            context.0, permissions.to_mem_flags().bits(), size as libc::size_t, ptr::null_mut(),
-            &mut err);
-        try!(check_status(err));
-        Ok(Mem(mem))
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub enum DeviceMemCacheType {
+    None,
+    ReadOnly,
+    ReadWrite,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct DeviceInfoGlobalMemCacheType;
+impl DeviceInfo for DeviceInfoGlobalMemCacheType {
+    type Info = DeviceMemCacheType;
+    fn get_device_info(self, device: DeviceId) -> Result<DeviceMemCacheType> {
+        unsafe {
+            let cache_type: cl::cl_device_mem_cache_type =
+                try!(get_device_info_value(device, cl::CL_DEVICE_GLOBAL_MEM_CACHE_TYPE));
+            match cache_type {
+                cl::CL_NONE => Ok(DeviceMemCacheType::None),
+                cl::CL_READ_ONLY_CACHE => Ok(DeviceMemCacheType::ReadOnly),
+                cl::CL_READ_WRITE_CACHE => Ok(DeviceMemCacheType::ReadWrite),
+                other => Err(Error::new("clGetDeviceInfo", CLStatus::CL_INVALID_VALUE)
+                    .with_message(format!("unknown global memory cache type {}", other))),
+            }
+        }
     }
 }
 
-impl Mem {
-    pub fn try_clone(&self) -> Result<Mem> {
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub enum DeviceLocalMemType {
+    /// Only custom devices have no local memory.
+    None,
+    /// Dedicated local memory.
+    Local,
+    /// Local memory emulated with global memory.
+    Global,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct DeviceInfoLocalMemType;
+impl DeviceInfo for DeviceInfoLocalMemType {
+    type Info = DeviceLocalMemType;
+    fn get_device_info(self, device: DeviceId) -> Result<DeviceLocalMemType> {
         unsafe {
-            try!(check_status(cl::ll::clRetainMemObject(self.0)));
-            Ok(Mem(self.0))
+            let mem_type: cl::cl_device_local_mem_type =
+                try!(get_device_info_value(device, cl::CL_DEVICE_LOCAL_MEM_TYPE));
+            match mem_type {
+                cl::CL_NONE => Ok(DeviceLocalMemType::None),
+                cl::CL_LOCAL => Ok(DeviceLocalMemType::Local),
+                cl::CL_GLOBAL => Ok(DeviceLocalMemType::Global),
+                other => Err(Error::new("clGetDeviceInfo", CLStatus::CL_INVALID_VALUE)
+                    .with_message(format!("unknown local memory type {}", other))),
+            }
         }
     }
 }
 
-impl Clone for Mem {
-    fn clone(&self) -> Mem {
-        match self.try_clone() {
-            Ok(mem) => mem,
-            Err(err) => panic!(
-                "Rascal: Failed to increment OpenCL mem object refcount! (Error: {:?})", err),
+#[derive(Debug, Copy, Clone)]
+pub struct DeviceInfoExecutionCapabilities;
+impl DeviceInfo for DeviceInfoExecutionCapabilities {
+    type Info = exec_capabilities::ExecCapabilities;
+    fn get_device_info(self, device: DeviceId) -> Result<exec_capabilities::ExecCapabilities> {
+        unsafe {
+            let capabilities: cl::cl_device_exec_capabilities =
+                try!(get_device_info_value(device, cl::CL_DEVICE_EXECUTION_CAPABILITIES));
+            Ok(exec_capabilities::ExecCapabilities::from_bits_truncate(capabilities))
         }
     }
 }
 
-impl Drop for Mem {
-    fn drop(&mut self) {
+/// The queue properties the device supports.
+#[derive(Debug, Copy, Clone)]
+pub struct DeviceInfoQueueProperties;
+impl DeviceInfo for DeviceInfoQueueProperties {
+    type Info = queue_properties::QueueProperties;
+    fn get_device_info(self, device: DeviceId) -> Result<queue_properties::QueueProperties> {
         unsafe {
-            match check_status(cl::ll::clReleaseMemObject(self.0)) {
-                Ok(()) => { }
-                Err(err) => panic!(
-                    "Rascal: Failed to decrement OpenCL mem object refcount! (Error: {:?})", err)
-            }
+            let properties: cl::cl_command_queue_properties =
+                try!(get_device_info_value(device, cl::CL_DEVICE_QUEUE_PROPERTIES));
+            Ok(queue_properties::QueueProperties::from_bits_truncate(properties))
         }
     }
 }
 
-pub fn create_command_queue(context: &Context, device: DeviceId,
-    properties: queue_properties::QueueProperties)
-    -> Result<CommandQueue>
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BuildStatus {
+    None,
+    Error,
+    Success,
+    InProgress,
+}
+
+/// Not in `opencl::cl` as of writing (they're OpenCL 1.2).
+const CL_PROGRAM_NUM_KERNELS: cl::cl_uint = 0x1167;
+const CL_PROGRAM_KERNEL_NAMES: cl::cl_uint = 0x1168;
+const CL_KERNEL_ATTRIBUTES: cl::cl_uint = 0x1195;
+
+pub trait ProgramInfo {
+    type Info;
+    fn get_program_info(self, program: &Program) -> Result<Self::Info>;
+}
+
+/// Reads a fixed-size program info value.
+unsafe fn get_program_info_value<T: Copy>(program: &Program, info: cl::cl_program_info)
+    -> Result<T>
 {
-    unsafe {
-        let mut err = 0;
-        let queue = cl::ll::clCreateCommandQueue(
-            context.0, device.0, properties.bits(), &mut err);
-        try!(check_status(err));
-        Ok(CommandQueue(queue))
+    let mut ret: T = mem::zeroed();
+    let res = cl::ll::clGetProgramInfo(
+        program.0, info, mem::size_of::<T>() as libc::size_t,
+        &mut ret as *mut _ as *mut _, ptr::null_mut());
+    try!(check_status("clGetProgramInfo", res));
+    Ok(ret)
+}
+
+/// Reads a program info value made of `T`s, however many there are.
+unsafe fn get_program_info_vec<T: Copy>(program: &Program, info: cl::cl_program_info)
+    -> Result<Vec<T>>
+{
+    let mut size = 0;
+    let res = cl::ll::clGetProgramInfo(program.0, info, 0, ptr::null_mut(), &mut size);
+    try!(check_status("clGetProgramInfo", res));
+    if size == 0 {
+        return Ok(Vec::new());
     }
+    let mut ret: Vec<T> = repeat(mem::zeroed()).take(size as usize / mem::size_of::<T>())
+        .collect();
+    let res = cl::ll::clGetProgramInfo(
+        program.0, info, (ret.len() * mem::size_of::<T>()) as libc::size_t,
+        ret.as_mut_ptr() as *mut _, ptr::null_mut());
+    try!(check_status("clGetProgramInfo", res));
+    Ok(ret)
 }
 
-impl CommandQueue {
-    pub fn try_clone(&self) -> Result<CommandQueue> {
-        unsafe {
-            try!(check_status(cl::ll::clRetainCommandQueue(self.0)));
-            Ok(CommandQueue(self.0))
-        }
+#[repr(u32)]
+#[derive(Debug, Copy, Clone)]
+pub enum ProgramInfoClUint {
+    ReferenceCount = cl::CL_PROGRAM_REFERENCE_COUNT,
+    NumDevices = cl::CL_PROGRAM_NUM_DEVICES,
+}
+
+impl ProgramInfo for ProgramInfoClUint {
+    type Info = cl::cl_uint;
+    fn get_program_info(self, program: &Program) -> Result<cl::cl_uint> {
+        unsafe { get_program_info_value(program, self as cl::cl_program_info) }
     }
 }
 
-impl Clone for CommandQueue {
-    fn clone(&self) -> CommandQueue {
-        match self.try_clone() {
-            Ok(queue) => queue,
-            Err(err) => panic!(
-                "Rascal: Failed to increment OpenCL command queue refcount! (Error: {:?})", err),
+/// The source the program was created from. Empty for programs created from binaries.
+#[derive(Debug, Copy, Clone)]
+pub struct ProgramInfoSource;
+impl ProgramInfo for ProgramInfoSource {
+    type Info = String;
+    fn get_program_info(self, program: &Program) -> Result<String> {
+        unsafe {
+            get_program_info_vec(program, cl::CL_PROGRAM_SOURCE).map(string_from_cstring_buf)
         }
     }
 }
 
-impl Drop for CommandQueue {
-    fn drop(&mut self) {
+#[derive(Debug, Copy, Clone)]
+pub struct ProgramInfoDevices;
+impl ProgramInfo for ProgramInfoDevices {
+    type Info = Vec<DeviceId>;
+    fn get_program_info(self, program: &Program) -> Result<Vec<DeviceId>> {
         unsafe {
-            match check_status(cl::ll::clReleaseCommandQueue(self.0)) {
-                Ok(()) => { }
-                Err(err) => panic!(
-                    "Rascal: Failed to decrement OpenCL command queue refcount! (Error: {:?})",
-                    err)
-            }
+            let ids: Vec<cl::cl_device_id> =
+                try!(get_program_info_vec(program, cl::CL_PROGRAM_DEVICES));
+            Ok(ids.into_iter().map(DeviceId).collect())
         }
     }
 }
 
-impl Context {
-    pub fn try_clone(&self) -> Result<Context> {
+/// The size of the binary for each of the program's devices, in the order of
+/// `ProgramInfoDevices`. Zero for devices the program hasn't been built for.
+#[derive(Debug, Copy, Clone)]
+pub struct ProgramInfoBinarySizes;
+impl ProgramInfo for ProgramInfoBinarySizes {
+    type Info = Vec<usize>;
+    fn get_program_info(self, program: &Program) -> Result<Vec<usize>> {
         unsafe {
-            try!(check_status(cl::ll::clRetainContext(self.0)));
-            Ok(Context(self.0))
+            let sizes: Vec<libc::size_t> =
+                try!(get_program_info_vec(program, cl::CL_PROGRAM_BINARY_SIZES));
+            Ok(sizes.into_iter().map(|size| size as usize).collect())
         }
     }
 }
 
-impl Clone for Context {
-    fn clone(&self) -> Context {
-        match self.try_clone() {
-            Ok(context) => context,
-            Err(err) => panic!(
-                "Rascal: Failed to increment OpenCL context refcount! (Error: {:?})", err),
+/// The number of kernel functions in the program. Only available once it's been built.
+#[derive(Debug, Copy, Clone)]
+pub struct ProgramInfoNumKernels;
+impl ProgramInfo for ProgramInfoNumKernels {
+    type Info = usize;
+    fn get_program_info(self, program: &Program) -> Result<usize> {
+        unsafe {
+            get_program_info_value::<libc::size_t>(program, CL_PROGRAM_NUM_KERNELS)
+                .map(|num| num as usize)
         }
     }
 }
 
-impl Drop for Context {
-    fn drop(&mut self) {
+/// The names of the program's kernel functions. Only available once it's been built.
+#[derive(Debug, Copy, Clone)]
+pub struct ProgramInfoKernelNames;
+impl ProgramInfo for ProgramInfoKernelNames {
+    type Info = Vec<String>;
+    fn get_program_info(self, program: &Program) -> Result<Vec<String>> {
         unsafe {
-            match check_status(cl::ll::clReleaseContext(self.0)) {
-                Ok(()) => { }
-                Err(err) => panic!(
-                    "Rascal: Failed to decrement OpenCL context refcount! (Error: {:?})", err)
-            }
+            let names = string_from_cstring_buf(
+                try!(get_program_info_vec(program, CL_PROGRAM_KERNEL_NAMES)));
+            Ok(names.split(';').filter(|name| !name.is_empty()).map(String::from).collect())
         }
     }
 }
+
+pub trait KernelInfo {
+    type Info;
+    fn get_kernel_info(self, kernel: &Kernel) -> Result<Self::Info>;
+}
+
+#[repr(u32)]
+#[derive(Debug, Copy, Clone)]
+pub enum KernelInfoString {
+    FunctionName = cl::CL_KERNEL_FUNCTION_NAME,
+    /// The `__attribute__`s the kernel function was declared with, space-separated.
+    Attributes = CL_KERNEL_ATTRIBUTES,
+}
+
+impl KernelInfo for KernelInfoString {
+    type Info = String;
+    fn get_kernel_info(self, kernel: &Kernel) -> Result<String> {
+        unsafe {
+            let mut str_len = 0;
+            let res = cl::ll::clGetKernelInfo(
+                kernel.0, self as cl::cl_kernel_info, 0, ptr::null_mut(), &mut str_len);
+            try!(check_status("clGetKernelInfo", res));
+            let mut bytes: Vec<_> = repeat(0).take(str_len as usize).collect();
+            let res = cl::ll::clGetKernelInfo(
+                kernel.0, self as cl::cl_kernel_info, bytes.len() as libc::size_t,
+                bytes.as_mut_ptr() as *mut _ as *mut _, ptr::null_mut());
+            try!(check_status("clGetKernelInfo", res));
+            Ok(string_from_cstring_buf(bytes))
+        }
+    }
+}
+
+#[repr(u32)]
+#[derive(Debug, Copy, Clone)]
+pub enum KernelInfoClUint {
+    NumArgs = cl::CL_KERNEL_NUM_ARGS,
+    ReferenceCount = cl::CL_KERNEL_REFERENCE_COUNT,
+}
+
+impl KernelInfo for KernelInfoClUint {
+    type Info = cl::cl_uint;
+    fn get_kernel_info(self, kernel: &Kernel) -> Result<cl::cl_uint> {
+        unsafe {
+            let mut ret = 0;
+            let res = cl::ll::clGetKernelInfo(
+                kernel.0, self as cl::cl_kernel_info,
+                mem::size_of::<cl::cl_uint>() as libc::size_t,
+                &mut ret as *mut _ as *mut _, ptr::null_mut());
+            try!(check_status("clGetKernelInfo", res));
+            Ok(ret)
+        }
+    }
+}
+
+/// Information about running a kernel on a particular device.
+pub trait KernelWorkGroupInfo {
+    type Info;
+    fn get_kernel_work_group_info(self, kernel: &Kernel, device: DeviceId)
+        -> Result<Self::Info>;
+}
+
+unsafe fn get_kernel_work_group_info_value<T: Copy>(kernel: &Kernel, device: DeviceId,
+    info: cl::cl_kernel_work_group_info)
+    -> Result<T>
+{
+    let mut ret: T = mem::zeroed();
+    let res = cl::ll::clGetKernelWorkGroupInfo(
+        kernel.0, device.0, info, mem::size_of::<T>() as libc::size_t,
+        &mut ret as *mut _ as *mut _, ptr::null_mut());
+    try!(check_status("clGetKernelWorkGroupInfo", res));
+    Ok(ret)
+}
+
+#[repr(u32)]
+#[derive(Debug, Copy, Clone)]
+pub enum KernelWorkGroupInfoSize {
+    /// The largest work-group the kernel can be launched with on the device, which may be
+    /// smaller than the device's own maximum.
+    WorkGroupSize = cl::CL_KERNEL_WORK_GROUP_SIZE,
+    /// Work-group sizes should be a multiple of this to perform well.
+    PreferredWorkGroupSizeMultiple = cl::CL_KERNEL_PREFERRED_WORK_GROUP_SIZE_MULTIPLE,
+}
+
+impl KernelWorkGroupInfo for KernelWorkGroupInfoSize {
+    type Info = usize;
+    fn get_kernel_work_group_info(self, kernel: &Kernel, device: DeviceId) -> Result<usize> {
+        unsafe {
+            get_kernel_work_group_info_value::<libc::size_t>(
+                kernel, device, self as cl::cl_kernel_work_group_info)
+                .map(|size| size as usize)
+        }
+    }
+}
+
+#[repr(u32)]
+#[derive(Debug, Copy, Clone)]
+pub enum KernelWorkGroupInfoClUlong {
+    /// Local memory used by the kernel itself, excluding `__local` arguments.
+    LocalMemSize = cl::CL_KERNEL_LOCAL_MEM_SIZE,
+    PrivateMemSize = cl::CL_KERNEL_PRIVATE_MEM_SIZE,
+}
+
+impl KernelWorkGroupInfo for KernelWorkGroupInfoClUlong {
+    type Info = cl::cl_ulong;
+    fn get_kernel_work_group_info(self, kernel: &Kernel, device: DeviceId)
+        -> Result<cl::cl_ulong>
+    {
+        unsafe {
+            get_kernel_work_group_info_value(kernel, device, self as cl::cl_kernel_work_group_info)
+        }
+    }
+}
+
+/// The work-group size given by `__attribute__((reqd_work_group_size(x, y, z)))`, or all
+/// zeroes if the kernel doesn't specify one.
+#[derive(Debug, Copy, Clone)]
+pub struct KernelWorkGroupInfoCompileWorkGroupSize;
+impl KernelWorkGroupInfo for KernelWorkGroupInfoCompileWorkGroupSize {
+    type Info = [usize; 3];
+    fn get_kernel_work_group_info(self, kernel: &Kernel, device: DeviceId)
+        -> Result<[usize; 3]>
+    {
+        unsafe {
+            let sizes: [libc::size_t; 3] = try!(get_kernel_work_group_info_value(
+                kernel, device, cl::CL_KERNEL_COMPILE_WORK_GROUP_SIZE));
+            Ok([sizes[0] as usize, sizes[1] as usize, sizes[2] as usize])
+        }
+    }
+}
+
+pub trait ProgramBuildInfo {
+    type Info;
+    fn get_program_build_info(self, program: &Program, device: DeviceId) -> Result<Self::Info>;
+}
+
+#[repr(u32)]
+#[derive(Debug, Copy, Clone)]
+pub enum ProgramBuildInfoString {
+    Options = cl::CL_PROGRAM_BUILD_OPTIONS,
+    Log = cl::CL_PROGRAM_BUILD_LOG,
+}
+
+impl ProgramBuildInfo for ProgramBuildInfoString {
+    type Info = String;
+    fn get_program_build_info(self, program: &Program, device: DeviceId) -> Result<String> {
+        unsafe {
+            let mut str_len = 0;
+            let res = cl::ll::clGetProgramBuildInfo(
+                program.0, device.0, self as cl::cl_program_build_info, 0, ptr::null_mut(),
+                &mut str_len);
+            try!(check_status("clGetProgramBuildInfo", res));
+            let mut bytes: Vec<_> = repeat(0).take(str_len as usize).collect();
+            let res = cl::ll::clGetProgramBuildInfo(
+                program.0, device.0, self as cl::cl_program_build_info,
+                bytes.len() as libc::size_t, bytes.as_mut_ptr() as *mut _ as *mut _,
+                ptr::null_mut());
+            try!(check_status("clGetProgramBuildInfo", res));
+            Ok(string_from_cstring_buf(bytes))
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct ProgramBuildInfoStatus;
+impl ProgramBuildInfo for ProgramBuildInfoStatus {
+    type Info = BuildStatus;
+    fn get_program_build_info(self, program: &Program, device: DeviceId) -> Result<BuildStatus> {
+        unsafe {
+            let mut status: cl::cl_build_status = 0;
+            let res = cl::ll::clGetProgramBuildInfo(
+                program.0, device.0, cl::CL_PROGRAM_BUILD_STATUS,
+                mem::size_of::<cl::cl_build_status>() as libc::size_t,
+                &mut status as *mut _ as *mut _, ptr::null_mut());
+            try!(check_status("clGetProgramBuildInfo", res));
+            match status {
+                cl::CL_BUILD_NONE => Ok(BuildStatus::None),
+                cl::CL_BUILD_ERROR => Ok(BuildStatus::Error),
+                cl::CL_BUILD_SUCCESS => Ok(BuildStatus::Success),
+                cl::CL_BUILD_IN_PROGRESS => Ok(BuildStatus::InProgress),
+                other => Err(Error::new("clGetProgramBuildInfo", CLStatus::CL_INVALID_VALUE)
+                    .with_message(format!("unknown build status {}", other))),
+            }
+        }
+    }
+}
+
+/// Returned by the ICD loader from `clGetPlatformIDs` when no platforms are installed. Not
+/// part of `CLStatus`, since it comes from the `cl_khr_icd` extension.
+pub const PLATFORM_NOT_FOUND_KHR: cl::cl_int = -1001;
+
+/// Returns every available platform, or an empty list if no OpenCL implementation is
+/// installed.
+pub fn get_platform_ids() -> Result<Vec<PlatformId>> {
+    unsafe {
+        let mut num_platforms = 0;
+        let res = cl::ll::clGetPlatformIDs(0, ptr::null_mut(), &mut num_platforms);
+        if res == PLATFORM_NOT_FOUND_KHR {
+            return Ok(Vec::new());
+        }
+        try!(check_status("clGetPlatformIDs", res));
+        let mut ids: Vec<_> = repeat(0 as *mut _).take(num_platforms as usize).collect();
+        let res = cl::ll::clGetPlatformIDs(
+            ids.len() as cl::cl_uint, ids.as_mut_ptr(), ptr::null_mut());
+        try!(check_status("clGetPlatformIDs", res));
+        Ok(ids.iter().map(|ptr| PlatformId(*ptr)).collect())
+    }
+}
+
+pub fn get_platform_info(platform: PlatformId, info: PlatformInfo) -> Result<String> {
+    unsafe {
+        let mut info_size = 0;
+        let res = cl::ll::clGetPlatformInfo(
+            platform.0, info as cl::cl_platform_info, 0, ptr::null_mut(), &mut info_size);
+        try!(check_status("clGetPlatformInfo", res));
+        let mut bytes: Vec<_> = repeat(0).take(info_size as usize).collect();
+        let res = cl::ll::clGetPlatformInfo(
+            platform.0, info as cl::cl_platform_info, bytes.len() as libc::size_t,
+            bytes.as_mut_ptr() as *mut _, ptr::null_mut());
+        try!(check_status("clGetPlatformInfo", res));
+        Ok(string_from_cstring_buf(bytes))
+    }
+}
+
+/// Returns the devices of `platform` matching `device_type`, or an empty list if there are
+/// none.
+pub fn get_device_ids(platform: PlatformId, device_type: DeviceType)
+    -> Result<Vec<DeviceId>>
+{
+    unsafe {
+        let mut num_devices = 0;
+        let res = cl::ll::clGetDeviceIDs(
+            platform.0, device_type.bits(), 0, ptr::null_mut(), &mut num_devices);
+        if res == CLStatus::CL_DEVICE_NOT_FOUND as cl::cl_int {
+            return Ok(Vec::new());
+        }
+        try!(check_status("clGetDeviceIDs", res));
+        let mut ids: Vec<_> = repeat(0 as *mut _).take(num_devices as usize).collect();
+        let res = cl::ll::clGetDeviceIDs(
+            platform.0, device_type.bits(), ids.len() as cl::cl_uint, ids.as_mut_ptr(),
+            ptr::null_mut());
+        try!(check_status("clGetDeviceIDs", res));
+        Ok(ids.iter().map(|ptr| DeviceId(*ptr)).collect())
+    }
+}
+
+pub fn get_device_info<T: DeviceInfo>(device: DeviceId, info: T) -> Result<T::Info> {
+    info.get_device_info(device)
+}
+
+pub fn get_program_build_info<T: ProgramBuildInfo>(program: &Program, device: DeviceId, info: T)
+    -> Result<T::Info>
+{
+    info.get_program_build_info(program, device)
+}
+
+pub fn get_context_info<T: ContextInfo>(context: &Context, info: T) -> Result<T::Info> {
+    info.get_context_info(context)
+}
+
+pub fn get_command_queue_info<T: CommandQueueInfo>(queue: &CommandQueue, info: T)
+    -> Result<T::Info>
+{
+    info.get_command_queue_info(queue)
+}
+
+pub fn get_program_info<T: ProgramInfo>(program: &Program, info: T) -> Result<T::Info> {
+    info.get_program_info(program)
+}
+
+pub fn get_kernel_info<T: KernelInfo>(kernel: &Kernel, info: T) -> Result<T::Info> {
+    info.get_kernel_info(kernel)
+}
+
+pub fn get_kernel_work_group_info<T: KernelWorkGroupInfo>(kernel: &Kernel, device: DeviceId,
+    info: T)
+    -> Result<T::Info>
+{
+    info.get_kernel_work_group_info(kernel, device)
+}
+
+/// Receives the errors a context reports while it's in use, such as out-of-resources
+/// failures during a kernel run. Called from driver threads, possibly concurrently.
+pub trait ContextNotify: Send + Sync {
+    /// `errinfo` is the driver's description of the error, and `private_info` is
+    /// implementation-specific data that may help debug it.
+    fn notify(&self, errinfo: &str, private_info: &[u8]);
+}
+
+impl<F: Fn(&str, &[u8]) + Send + Sync> ContextNotify for F {
+    fn notify(&self, errinfo: &str, private_info: &[u8]) {
+        self(errinfo, private_info)
+    }
+}
+
+/// A context error, as sent down a channel by the `ContextNotify` impls for `Sender` and
+/// `SyncSender`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContextNotification {
+    pub errinfo: String,
+    pub private_info: Vec<u8>,
+}
+
+impl ContextNotify for mpsc::Sender<ContextNotification> {
+    fn notify(&self, errinfo: &str, private_info: &[u8]) {
+        // nobody is listening anymore, which is their business.
+        let _ = self.send(ContextNotification {
+            errinfo: errinfo.to_string(),
+            private_info: private_info.to_vec(),
+        });
+    }
+}
+
+impl ContextNotify for mpsc::SyncSender<ContextNotification> {
+    fn notify(&self, errinfo: &str, private_info: &[u8]) {
+        // never block the driver's thread; if the channel is full the error is dropped.
+        let _ = self.try_send(ContextNotification {
+            errinfo: errinfo.to_string(),
+            private_info: private_info.to_vec(),
+        });
+    }
+}
+
+/// Writes context errors to stderr. What contexts use unless told otherwise.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct LogNotify;
+
+impl ContextNotify for LogNotify {
+    fn notify(&self, errinfo: &str, private_info: &[u8]) {
+        let _ = private_info;
+        let _ = writeln!(io::stderr(), "Rascal: Got error from OpenCL context! (Error: {})",
+            errinfo);
+    }
+}
+
+type ContextNotifyFn = extern "C" fn(*const libc::c_char, *const libc::c_void, libc::size_t,
+    *mut libc::c_void);
+
+unsafe fn call_context_notify(notify: &dyn ContextNotify, errinfo: *const libc::c_char,
+    private_info: *const libc::c_void, cb: libc::size_t)
+{
+    let errinfo = CStr::from_ptr(errinfo).to_string_lossy();
+    let private_info = if private_info.is_null() {
+        &[][..]
+    } else {
+        slice::from_raw_parts(private_info as *const u8, cb as usize)
+    };
+    // unwinding into the driver is undefined behaviour, so any panic stops here.
+    let _ = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+        notify.notify(&errinfo, private_info)
+    }));
+}
+
+extern "C" fn context_notify_handler(errinfo: *const libc::c_char,
+    private_info: *const libc::c_void, cb: libc::size_t, user_data: *mut libc::c_void)
+{
+    unsafe {
+        let notify = &*(user_data as *const Box<dyn ContextNotify>);
+        call_context_notify(&**notify, errinfo, private_info, cb)
+    }
+}
+
+/// Used instead of `context_notify_handler` for `LogNotify`, which has no state to pass.
+extern "C" fn log_notify_handler(errinfo: *const libc::c_char,
+    private_info: *const libc::c_void, cb: libc::size_t, _user_data: *mut libc::c_void)
+{
+    unsafe { call_context_notify(&LogNotify, errinfo, private_info, cb) }
+}
+
+/// Not in `opencl::cl` as of writing (it's OpenCL 1.2).
+const CL_CONTEXT_INTEROP_USER_SYNC: cl::cl_context_properties = 0x1085;
+
+/// The properties to create a context with.
+///
+/// ```ignore
+/// let properties = ContextProperties::new().platform(platform);
+/// let context = try!(create_context_from_type(&properties, device_type::GPU, LogNotify));
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ContextProperties {
+    platform: Option<PlatformId>,
+    interop_user_sync: Option<bool>,
+    other: Vec<(cl::cl_context_properties, cl::cl_context_properties)>,
+}
+
+impl ContextProperties {
+    pub fn new() -> ContextProperties {
+        ContextProperties::default()
+    }
+
+    /// The platform to create the context on. Without one, which platform gets picked is up
+    /// to the ICD loader.
+    pub fn platform(mut self, platform: PlatformId) -> ContextProperties {
+        self.platform = Some(platform);
+        self
+    }
+
+    /// Whether the application, rather than OpenCL, is responsible for synchronising with
+    /// graphics API objects shared with the context.
+    pub fn interop_user_sync(mut self, user_sync: bool) -> ContextProperties {
+        self.interop_user_sync = Some(user_sync);
+        self
+    }
+
+    /// Adds a property by its raw key, such as one from a sharing extension. Keys with a
+    /// method of their own (like `CL_CONTEXT_PLATFORM`) are set as if through that method.
+    /// Replaces any earlier value for `key`.
+    pub fn property(mut self, key: cl::cl_context_properties, value: cl::cl_context_properties)
+        -> ContextProperties
+    {
+        self.set(key, value);
+        self
+    }
+
+    fn set(&mut self, key: cl::cl_context_properties, value: cl::cl_context_properties) {
+        if key == cl::CL_CONTEXT_PLATFORM as cl::cl_context_properties {
+            self.platform = Some(PlatformId(value as usize as cl::cl_platform_id));
+        } else if key == CL_CONTEXT_INTEROP_USER_SYNC {
+            self.interop_user_sync = Some(value != 0);
+        } else {
+            self.other.retain(|&(k, _)| k != key);
+            self.other.push((key, value));
+        }
+    }
+
+    pub fn get_platform(&self) -> Option<PlatformId> {
+        self.platform
+    }
+
+    pub fn get_interop_user_sync(&self) -> Option<bool> {
+        self.interop_user_sync
+    }
+
+    pub fn get_property(&self, key: cl::cl_context_properties)
+        -> Option<cl::cl_context_properties>
+    {
+        if key == cl::CL_CONTEXT_PLATFORM as cl::cl_context_properties {
+            self.platform.map(|platform| platform.0 as usize as cl::cl_context_properties)
+        } else if key == CL_CONTEXT_INTEROP_USER_SYNC {
+            self.interop_user_sync.map(|user_sync| user_sync as cl::cl_context_properties)
+        } else {
+            self.other.iter().find(|&&(k, _)| k == key).map(|&(_, v)| v)
+        }
+    }
+
+    /// Reads a zero-terminated list of key-value pairs, as returned by `clGetContextInfo`.
+    fn from_raw(raw: &[cl::cl_context_properties]) -> ContextProperties {
+        let mut properties = ContextProperties::new();
+        for pair in raw.chunks(2) {
+            if pair.len() < 2 || pair[0] == 0 {
+                break;
+            }
+            properties.set(pair[0], pair[1]);
+        }
+        properties
+    }
+
+    /// The zero-terminated list of key-value pairs OpenCL takes, or an empty list if there are
+    /// no properties at all.
+    fn to_raw(&self) -> Vec<cl::cl_context_properties> {
+        let mut raw = Vec::new();
+        if let Some(platform) = self.platform {
+            raw.push(cl::CL_CONTEXT_PLATFORM as cl::cl_context_properties);
+            raw.push(platform.0 as usize as cl::cl_context_properties);
+        }
+        if let Some(user_sync) = self.interop_user_sync {
+            raw.push(CL_CONTEXT_INTEROP_USER_SYNC);
+            raw.push(user_sync as cl::cl_context_properties);
+        }
+        for &(key, value) in self.other.iter() {
+            raw.push(key);
+            raw.push(value);
+        }
+        if !raw.is_empty() {
+            raw.push(0);
+        }
+        raw
+    }
+}
+
+/// The callback and user data to hand the driver for `notify`. `LogNotify` gets a callback of
+/// its own and no user data, so the default costs nothing; anything else is boxed.
+fn context_notify_callback<N: ContextNotify + 'static>(notify: N)
+    -> (ContextNotifyFn, *mut Box<dyn ContextNotify>)
+{
+    if TypeId::of::<N>() == TypeId::of::<LogNotify>() {
+        (log_notify_handler, ptr::null_mut())
+    } else {
+        let notify: Box<Box<dyn ContextNotify>> = Box::new(Box::new(notify));
+        (context_notify_handler, Box::into_raw(notify))
+    }
+}
+
+/// Creates a context for `devices`, passing any errors it reports to `notify`.
+///
+/// Unless it's `LogNotify`, `notify` is leaked: contexts are reference counted by the driver
+/// (queues and programs keep theirs alive too), and OpenCL gives us no reliable way to learn
+/// when one is finally destroyed. That includes anything it owns, so a `Sender` keeps its
+/// channel open for good. Code creating many contexts should share one notifier between them
+/// (e.g. a closure around an `Arc`) rather than build a fresh one each time.
+pub fn create_context<N: ContextNotify + 'static>(properties: &ContextProperties,
+    devices: &[DeviceId], notify: N)
+    -> Result<Context>
+{
+    unsafe {
+        let mut err = 0;
+        let props = properties.to_raw();
+        let props_ptr = if props.is_empty() { ptr::null() } else { props.as_ptr() };
+        let ids: Vec<_> = devices.iter().map(|d| d.0).collect();
+        let (callback, user_data) = context_notify_callback(notify);
+        let context = cl::ll::clCreateContext(
+            props_ptr, ids.len() as cl::cl_uint, ids[..].as_ptr(),
+            callback, user_data as *mut _,
+            &mut err as *mut _);
+        if let Err(err) = check_status("clCreateContext", err) {
+            if !user_data.is_null() {
+                drop(Box::from_raw(user_data));
+            }
+            return Err(err);
+        }
+        Ok(Context(context))
+    }
+}
+
+/// Creates a context for every device of type `device_type`, passing any errors it reports to
+/// `notify`. Fails with `CL_DEVICE_NOT_FOUND` if there are none.
+///
+/// Unless it's `LogNotify`, `notify` is leaked, as with `create_context`.
+pub fn create_context_from_type<N: ContextNotify + 'static>(properties: &ContextProperties,
+    device_type: DeviceType, notify: N)
+    -> Result<Context>
+{
+    unsafe {
+        let mut err = 0;
+        let props = properties.to_raw();
+        let props_ptr = if props.is_empty() { ptr::null() } else { props.as_ptr() };
+        let (callback, user_data) = context_notify_callback(notify);
+        let context = ffi::clCreateContextFromType(
+            props_ptr, device_type.bits(), Some(callback),
+            user_data as *mut _, &mut err);
+        if let Err(err) = check_status("clCreateContextFromType", err) {
+            if !user_data.is_null() {
+                drop(Box::from_raw(user_data));
+            }
+            return Err(err);
+        }
+        Ok(Context(context))
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub enum MemProt {
+    ReadWrite,
+    ReadOnly,
+    WriteOnly,
+}
+
+impl MemProt {
+    pub fn to_mem_flags(self) -> mem_flags::MemFlags {
+        match self {
+            MemProt::ReadWrite => mem_flags::READ_WRITE,
+            MemProt::ReadOnly => mem_flags::READ_ONLY,
+            MemProt::WriteOnly => mem_flags::WRITE_ONLY,
+        }
+    }
+}
+
+unsafe fn create_buffer_raw(context: &Context, flags: mem_flags::MemFlags, size: usize,
+    host_ptr: *mut libc::c_void)
+    -> Result<Mem>
+{
+    let mut err = 0;
+    let mem = cl::ll::clCreateBuffer(
+        context.0, flags.bits(), size as libc::size_t, host_ptr, &mut err);
+    try!(check_status("clCreateBuffer", err));
+    Ok(Mem(mem))
+}
+
+/// Creates an entirely device-backed buffer.
+pub fn create_mem_device_buffer(context: &Context, permissions: MemProt, size: usize)
+    -> Result<Mem>
+{
+    unsafe {
+        create_buffer_raw(context, permissions.to_mem_flags(), size, ptr::null_mut())
+    }
+}
+
+/// Creates a device buffer initialised with a copy of `data`.
+pub fn create_mem_buffer_copy_host<T: Pod>(context: &Context, permissions: MemProt, data: &[T])
+    -> Result<Mem>
+{
+    unsafe {
+        create_buffer_raw(
+            context, permissions.to_mem_flags() | mem_flags::COPY_HOST_PTR,
+            data.len() * mem::size_of::<T>(), data.as_ptr() as *mut _)
+    }
+}
+
+/// Creates a buffer in host-accessible memory allocated by the driver (usually pinned, which
+/// makes transfers and mapping cheaper).
+pub fn create_mem_buffer_alloc_host(context: &Context, permissions: MemProt, size: usize)
+    -> Result<Mem>
+{
+    unsafe {
+        create_buffer_raw(
+            context, permissions.to_mem_flags() | mem_flags::ALLOC_HOST_PTR, size,
+            ptr::null_mut())
+    }
+}
+
+/// Creates a buffer that uses `data` itself as its storage.
+///
+/// Unsafe because the driver holds on to `data`: the caller must keep it alive, and not access
+/// it except through mapping, until the buffer (including every clone of it) has been released
+/// and every command using it has finished.
+pub unsafe fn create_mem_buffer_use_host<T: Pod>(context: &Context, permissions: MemProt,
+    data: &mut [T])
+    -> Result<Mem>
+{
+    create_buffer_raw(
+        context, permissions.to_mem_flags() | mem_flags::USE_HOST_PTR,
+        data.len() * mem::size_of::<T>(), data.as_mut_ptr() as *mut _)
+}
+
+impl Mem {
+    pub fn try_clone(&self) -> Result<Mem> {
+        unsafe {
+            try!(check_status("clRetainMemObject", cl::ll::clRetainMemObject(self.0)));
+            Ok(Mem(self.0))
+        }
+    }
+}
+
+impl Clone for Mem {
+    fn clone(&self) -> Mem {
+        match self.try_clone() {
+            Ok(mem) => mem,
+            Err(err) => panic!(
+                "Rascal: Failed to increment OpenCL mem object refcount! (Error: {})", err),
+        }
+    }
+}
+
+impl Drop for Mem {
+    fn drop(&mut self) {
+        unsafe {
+            match check_status("clReleaseMemObject", cl::ll::clReleaseMemObject(self.0)) {
+                Ok(()) => { }
+                Err(err) => panic!(
+                    "Rascal: Failed to decrement OpenCL mem object refcount! (Error: {})", err)
+            }
+        }
+    }
+}
+
+/// A wait list in the form the driver wants it, i.e. a null pointer rather than an empty
+/// list.
+struct RawWaitList(Vec<cl::cl_event>);
+
+impl RawWaitList {
+    fn new(events: &[Event]) -> RawWaitList {
+        RawWaitList(events.iter().map(|e| e.0).collect())
+    }
+
+    fn len(&self) -> cl::cl_uint {
+        self.0.len() as cl::cl_uint
+    }
+
+    fn as_ptr(&self) -> *const cl::cl_event {
+        if self.0.is_empty() { ptr::null() } else { self.0.as_ptr() }
+    }
+}
+
+/// Returns the size of `mem` in bytes.
+pub fn get_mem_size(mem: &Mem) -> Result<usize> {
+    unsafe {
+        let mut size: libc::size_t = 0;
+        let res = cl::ll::clGetMemObjectInfo(
+            mem.0, cl::CL_MEM_SIZE, mem::size_of::<libc::size_t>() as libc::size_t,
+            &mut size as *mut _ as *mut _, ptr::null_mut());
+        try!(check_status("clGetMemObjectInfo", res));
+        Ok(size as usize)
+    }
+}
+
+/// Checks that the `len` bytes starting at `offset` lie inside `mem`, so we fail before the
+/// driver gets a chance to read or write out of bounds.
+fn check_mem_range(function: &'static str, mem: &Mem, offset: usize, len: usize) -> Result<()> {
+    let size = try!(get_mem_size(mem));
+    match offset.checked_add(len) {
+        Some(end) if end <= size => Ok(()),
+        _ => Err(Error::new(function, CLStatus::CL_INVALID_VALUE).with_message(format!(
+            "range of {} bytes at offset {} is outside the buffer's {} bytes",
+            len, offset, size))),
+    }
+}
+
+unsafe fn enqueue_read_buffer_raw<T: Pod>(queue: &CommandQueue, mem: &Mem, blocking: bool,
+    offset: usize, data: &mut [T], wait_list: &[Event])
+    -> Result<Event>
+{
+    let len = data.len() * mem::size_of::<T>();
+    try!(check_mem_range("clEnqueueReadBuffer", mem, offset, len));
+    let wait_list = RawWaitList::new(wait_list);
+    let mut event = ptr::null_mut();
+    let res = cl::ll::clEnqueueReadBuffer(
+        queue.0, mem.0, if blocking { cl::CL_TRUE } else { cl::CL_FALSE },
+        offset as libc::size_t, len as libc::size_t, data.as_mut_ptr() as *mut _,
+        wait_list.len(), wait_list.as_ptr(), &mut event);
+    try!(check_status("clEnqueueReadBuffer", res));
+    Ok(Event(event))
+}
+
+unsafe fn enqueue_write_buffer_raw<T: Pod>(queue: &CommandQueue, mem: &Mem, blocking: bool,
+    offset: usize, data: &[T], wait_list: &[Event])
+    -> Result<Event>
+{
+    let len = data.len() * mem::size_of::<T>();
+    try!(check_mem_range("clEnqueueWriteBuffer", mem, offset, len));
+    let wait_list = RawWaitList::new(wait_list);
+    let mut event = ptr::null_mut();
+    let res = cl::ll::clEnqueueWriteBuffer(
+        queue.0, mem.0, if blocking { cl::CL_TRUE } else { cl::CL_FALSE },
+        offset as libc::size_t, len as libc::size_t, data.as_ptr() as *const _,
+        wait_list.len(), wait_list.as_ptr(), &mut event);
+    try!(check_status("clEnqueueWriteBuffer", res));
+    Ok(Event(event))
+}
+
+/// Reads from `mem`, starting `offset` bytes in, until `data` is full.
+/// Blocks until the read is finished.
+pub fn enqueue_read_buffer<T: Pod>(queue: &CommandQueue, mem: &Mem, offset: usize,
+    data: &mut [T], wait_list: &[Event])
+    -> Result<Event>
+{
+    unsafe { enqueue_read_buffer_raw(queue, mem, true, offset, data, wait_list) }
+}
+
+/// Like `enqueue_read_buffer`, but returns as soon as the read is enqueued.
+///
+/// Unsafe because the driver keeps writing into `data` after this returns: the caller must
+/// neither touch nor free it until the returned event completes.
+pub unsafe fn enqueue_read_buffer_nonblocking<T: Pod>(queue: &CommandQueue, mem: &Mem,
+    offset: usize, data: &mut [T], wait_list: &[Event])
+    -> Result<Event>
+{
+    enqueue_read_buffer_raw(queue, mem, false, offset, data, wait_list)
+}
+
+/// Writes all of `data` into `mem`, starting `offset` bytes in.
+/// Blocks until the write is finished.
+pub fn enqueue_write_buffer<T: Pod>(queue: &CommandQueue, mem: &Mem, offset: usize,
+    data: &[T], wait_list: &[Event])
+    -> Result<Event>
+{
+    unsafe { enqueue_write_buffer_raw(queue, mem, true, offset, data, wait_list) }
+}
+
+/// Like `enqueue_write_buffer`, but returns as soon as the write is enqueued.
+///
+/// Unsafe because the driver may still be reading `data` after this returns: the caller must
+/// neither modify nor free it until the returned event completes.
+pub unsafe fn enqueue_write_buffer_nonblocking<T: Pod>(queue: &CommandQueue, mem: &Mem,
+    offset: usize, data: &[T], wait_list: &[Event])
+    -> Result<Event>
+{
+    enqueue_write_buffer_raw(queue, mem, false, offset, data, wait_list)
+}
+
+/// Copies `size` bytes from `src` (starting `src_offset` bytes in) to `dst` (starting
+/// `dst_offset` bytes in).
+pub fn enqueue_copy_buffer(queue: &CommandQueue, src: &Mem, dst: &Mem, src_offset: usize,
+    dst_offset: usize, size: usize, wait_list: &[Event])
+    -> Result<Event>
+{
+    unsafe {
+        try!(check_mem_range("clEnqueueCopyBuffer", src, src_offset, size));
+        try!(check_mem_range("clEnqueueCopyBuffer", dst, dst_offset, size));
+        let wait_list = RawWaitList::new(wait_list);
+        let mut event = ptr::null_mut();
+        let res = cl::ll::clEnqueueCopyBuffer(
+            queue.0, src.0, dst.0, src_offset as libc::size_t, dst_offset as libc::size_t,
+            size as libc::size_t, wait_list.len(), wait_list.as_ptr(), &mut event);
+        try!(check_status("clEnqueueCopyBuffer", res));
+        Ok(Event(event))
+    }
+}
+
+/// Fills `size` bytes of `mem` (starting `offset` bytes in) with copies of `pattern`.
+/// `offset` and `size` must be multiples of the size of `T`.
+pub fn enqueue_fill_buffer<T: Pod>(queue: &CommandQueue, mem: &Mem, pattern: &T,
+    offset: usize, size: usize, wait_list: &[Event])
+    -> Result<Event>
+{
+    unsafe {
+        try!(check_mem_range("clEnqueueFillBuffer", mem, offset, size));
+        let wait_list = RawWaitList::new(wait_list);
+        let mut event = ptr::null_mut();
+        let res = ffi::clEnqueueFillBuffer(
+            queue.0, mem.0, pattern as *const T as *const _,
+            mem::size_of::<T>() as libc::size_t, offset as libc::size_t,
+            size as libc::size_t, wait_list.len(), wait_list.as_ptr(), &mut event);
+        try!(check_status("clEnqueueFillBuffer", res));
+        Ok(Event(event))
+    }
+}
+
+/// A host view of part of a buffer, mapped for reading. Unmapped when dropped.
+pub struct MappedRead<'a, T: Pod + 'a> {
+    queue: &'a CommandQueue,
+    mem: &'a Mem,
+    ptr: *const T,
+    len: usize,
+}
+
+/// A host view of part of a buffer, mapped for reading and writing. Unmapped when dropped,
+/// at which point the writes become visible to the device.
+pub struct MappedWrite<'a, T: Pod + 'a> {
+    queue: &'a CommandQueue,
+    mem: &'a Mem,
+    ptr: *mut T,
+    len: usize,
+}
+
+unsafe fn enqueue_map_buffer_raw<T: Pod>(queue: &CommandQueue, mem: &Mem,
+    flags: map_flags::MapFlags, offset: usize, len: usize, wait_list: &[Event])
+    -> Result<*mut T>
+{
+    let size = match len.checked_mul(mem::size_of::<T>()) {
+        Some(size) => size,
+        None => return Err(Error::new("clEnqueueMapBuffer", CLStatus::CL_INVALID_VALUE)
+            .with_message("mapped size overflows usize")),
+    };
+    try!(check_mem_range("clEnqueueMapBuffer", mem, offset, size));
+    let wait_list = RawWaitList::new(wait_list);
+    let mut err = 0;
+    let ptr = cl::ll::clEnqueueMapBuffer(
+        queue.0, mem.0, cl::CL_TRUE, flags.bits(), offset as libc::size_t,
+        size as libc::size_t, wait_list.len(), wait_list.as_ptr(), ptr::null_mut(), &mut err);
+    try!(check_status("clEnqueueMapBuffer", err));
+    if ptr as usize % mem::align_of::<T>() != 0 {
+        try!(enqueue_unmap_raw(queue, mem, ptr));
+        return Err(Error::new("clEnqueueMapBuffer", CLStatus::CL_INVALID_VALUE)
+            .with_message("mapped pointer isn't aligned for the element type"));
+    }
+    Ok(ptr as *mut T)
+}
+
+unsafe fn enqueue_unmap_raw(queue: &CommandQueue, mem: &Mem, ptr: *mut libc::c_void)
+    -> Result<()>
+{
+    check_status("clEnqueueUnmapMemObject", cl::ll::clEnqueueUnmapMemObject(
+        queue.0, mem.0, ptr, 0, ptr::null(), ptr::null_mut()))
+}
+
+/// Maps `len` values of `mem`, starting `offset` bytes in, for reading.
+/// Blocks until the mapping is available.
+///
+/// Unsafe because nothing stops the buffer being used while it's mapped: `Mem` handles are
+/// cheap to clone, and kernels keep theirs after `set_arg`. For buffers backed by host memory
+/// the mapping is that memory itself, so the caller must not enqueue (or leave running) any
+/// command that writes to the mapped region until the returned guard is dropped.
+pub unsafe fn enqueue_map_buffer<'a, T: Pod>(queue: &'a CommandQueue, mem: &'a Mem,
+    offset: usize, len: usize, wait_list: &[Event])
+    -> Result<MappedRead<'a, T>>
+{
+    let ptr = try!(enqueue_map_buffer_raw(
+        queue, mem, map_flags::READ, offset, len, wait_list));
+    Ok(MappedRead { queue: queue, mem: mem, ptr: ptr, len: len })
+}
+
+/// Maps `len` values of `mem`, starting `offset` bytes in, for reading and writing.
+/// Blocks until the mapping is available.
+///
+/// Unsafe for the same reason as `enqueue_map_buffer`, except that the caller must not have
+/// any command read or write the mapped region until the returned guard is dropped.
+pub unsafe fn enqueue_map_buffer_mut<'a, T: Pod>(queue: &'a CommandQueue, mem: &'a Mem,
+    offset: usize, len: usize, wait_list: &[Event])
+    -> Result<MappedWrite<'a, T>>
+{
+    let ptr = try!(enqueue_map_buffer_raw(
+        queue, mem, map_flags::READ | map_flags::WRITE, offset, len, wait_list));
+    Ok(MappedWrite { queue: queue, mem: mem, ptr: ptr, len: len })
+}
+
+impl<'a, T: Pod> Deref for MappedRead<'a, T> {
+    type Target = [T];
+    fn deref(&self) -> &[T] {
+        unsafe { slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl<'a, T: Pod> Drop for MappedRead<'a, T> {
+    fn drop(&mut self) {
+        unsafe {
+            match enqueue_unmap_raw(self.queue, self.mem, self.ptr as *mut _) {
+                Ok(()) => { }
+                Err(err) => panic!(
+                    "Rascal: Failed to unmap OpenCL mem object! (Error: {})", err)
+            }
+        }
+    }
+}
+
+impl<'a, T: Pod> Deref for MappedWrite<'a, T> {
+    type Target = [T];
+    fn deref(&self) -> &[T] {
+        unsafe { slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl<'a, T: Pod> DerefMut for MappedWrite<'a, T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        unsafe { slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+}
+
+impl<'a, T: Pod> Drop for MappedWrite<'a, T> {
+    fn drop(&mut self) {
+        unsafe {
+            match enqueue_unmap_raw(self.queue, self.mem, self.ptr as *mut _) {
+                Ok(()) => { }
+                Err(err) => panic!(
+                    "Rascal: Failed to unmap OpenCL mem object! (Error: {})", err)
+            }
+        }
+    }
+}
+
+pub fn create_command_queue(context: &Context, device: DeviceId,
+    properties: queue_properties::QueueProperties)
+    -> Result<CommandQueue>
+{
+    unsafe {
+        let mut err = 0;
+        let queue = cl::ll::clCreateCommandQueue(
+            context.0, device.0, properties.bits(), &mut err);
+        try!(check_status("clCreateCommandQueue", err));
+        Ok(CommandQueue(queue))
+    }
+}
+
+/// The extent of a kernel launch (or its offset, or its work-group size) in one to three
+/// dimensions.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct NDRange {
+    dims: u32,
+    sizes: [usize; 3],
+}
+
+impl NDRange {
+    pub fn dims(&self) -> u32 {
+        self.dims
+    }
+
+    pub fn sizes(&self) -> &[usize] {
+        &self.sizes[..self.dims as usize]
+    }
+}
+
+impl From<usize> for NDRange {
+    fn from(x: usize) -> NDRange {
+        NDRange { dims: 1, sizes: [x, 1, 1] }
+    }
+}
+
+impl From<[usize; 1]> for NDRange {
+    fn from(sizes: [usize; 1]) -> NDRange {
+        NDRange { dims: 1, sizes: [sizes[0], 1, 1] }
+    }
+}
+
+impl From<[usize; 2]> for NDRange {
+    fn from(sizes: [usize; 2]) -> NDRange {
+        NDRange { dims: 2, sizes: [sizes[0], sizes[1], 1] }
+    }
+}
+
+impl From<[usize; 3]> for NDRange {
+    fn from(sizes: [usize; 3]) -> NDRange {
+        NDRange { dims: 3, sizes: sizes }
+    }
+}
+
+impl From<(usize, usize)> for NDRange {
+    fn from((x, y): (usize, usize)) -> NDRange {
+        NDRange { dims: 2, sizes: [x, y, 1] }
+    }
+}
+
+impl From<(usize, usize, usize)> for NDRange {
+    fn from((x, y, z): (usize, usize, usize)) -> NDRange {
+        NDRange { dims: 3, sizes: [x, y, z] }
+    }
+}
+
+/// Enqueues `kernel` over `global_size` work-items. If `local_size` is `None` the
+/// implementation picks the work-group size. `global_offset` and `local_size` must have the
+/// same number of dimensions as `global_size`.
+pub fn enqueue_nd_range_kernel(queue: &CommandQueue, kernel: &Kernel,
+    global_offset: Option<NDRange>, global_size: NDRange, local_size: Option<NDRange>,
+    wait_list: &[Event])
+    -> Result<Event>
+{
+    unsafe {
+        let dims = global_size.dims();
+        let to_raw = |range: NDRange| -> Vec<libc::size_t> {
+            range.sizes().iter().map(|&size| size as libc::size_t).collect()
+        };
+        let offset = match global_offset {
+            Some(offset) if offset.dims() != dims =>
+                return Err(Error::new(
+                    "clEnqueueNDRangeKernel", CLStatus::CL_INVALID_GLOBAL_OFFSET)
+                    .with_message("global offset and global size differ in dimension")),
+            Some(offset) => Some(to_raw(offset)),
+            None => None,
+        };
+        let local = match local_size {
+            Some(local) if local.dims() != dims =>
+                return Err(Error::new(
+                    "clEnqueueNDRangeKernel", CLStatus::CL_INVALID_WORK_GROUP_SIZE)
+                    .with_message("local size and global size differ in dimension")),
+            Some(local) => Some(to_raw(local)),
+            None => None,
+        };
+        let global = to_raw(global_size);
+        let wait_list = RawWaitList::new(wait_list);
+        let mut event = ptr::null_mut();
+        let res = cl::ll::clEnqueueNDRangeKernel(
+            queue.0, kernel.0, dims as cl::cl_uint,
+            offset.as_ref().map_or(ptr::null(), |offset| offset.as_ptr()),
+            global.as_ptr(),
+            local.as_ref().map_or(ptr::null(), |local| local.as_ptr()),
+            wait_list.len(), wait_list.as_ptr(), &mut event);
+        try!(check_status("clEnqueueNDRangeKernel", res));
+        Ok(Event(event))
+    }
+}
+
+impl CommandQueue {
+    pub fn try_clone(&self) -> Result<CommandQueue> {
+        unsafe {
+            try!(check_status(
+                "clRetainCommandQueue", cl::ll::clRetainCommandQueue(self.0)));
+            Ok(CommandQueue(self.0))
+        }
+    }
+}
+
+impl Clone for CommandQueue {
+    fn clone(&self) -> CommandQueue {
+        match self.try_clone() {
+            Ok(queue) => queue,
+            Err(err) => panic!(
+                "Rascal: Failed to increment OpenCL command queue refcount! (Error: {})", err),
+        }
+    }
+}
+
+impl Drop for CommandQueue {
+    fn drop(&mut self) {
+        unsafe {
+            match check_status(
+                "clReleaseCommandQueue", cl::ll::clReleaseCommandQueue(self.0))
+            {
+                Ok(()) => { }
+                Err(err) => panic!(
+                    "Rascal: Failed to decrement OpenCL command queue refcount! (Error: {})",
+                    err)
+            }
+        }
+    }
+}
+
+impl Context {
+    pub fn try_clone(&self) -> Result<Context> {
+        unsafe {
+            try!(check_status("clRetainContext", cl::ll::clRetainContext(self.0)));
+            Ok(Context(self.0))
+        }
+    }
+}
+
+impl Clone for Context {
+    fn clone(&self) -> Context {
+        match self.try_clone() {
+            Ok(context) => context,
+            Err(err) => panic!(
+                "Rascal: Failed to increment OpenCL context refcount! (Error: {})", err),
+        }
+    }
+}
+
+impl Drop for Context {
+    fn drop(&mut self) {
+        unsafe {
+            match check_status("clReleaseContext", cl::ll::clReleaseContext(self.0)) {
+                Ok(()) => { }
+                Err(err) => panic!(
+                    "Rascal: Failed to decrement OpenCL context refcount! (Error: {})", err)
+            }
+        }
+    }
+}
+
+/// Creates a program from the concatenation of `sources`.
+pub fn create_program_with_source(context: &Context, sources: &[&str]) -> Result<Program> {
+    unsafe {
+        let mut err = 0;
+        let strings: Vec<_> = sources.iter().map(|s| s.as_ptr() as *const libc::c_char).collect();
+        let lengths: Vec<_> = sources.iter().map(|s| s.len() as libc::size_t).collect();
+        let program = cl::ll::clCreateProgramWithSource(
+            context.0, strings.len() as cl::cl_uint, strings.as_ptr(), lengths.as_ptr(),
+            &mut err);
+        try!(check_status("clCreateProgramWithSource", err));
+        Ok(Program(program))
+    }
+}
+
+/// Creates a program from binaries previously returned by `get_program_binaries`, one for
+/// each device in `devices`. The program still has to be built before kernels can be created
+/// from it. Fails with `CL_INVALID_BINARY` if a device doesn't accept its binary, which is
+/// to be expected after a driver update.
+pub fn create_program_with_binary(context: &Context, devices: &[DeviceId], binaries: &[&[u8]])
+    -> Result<Program>
+{
+    if devices.len() != binaries.len() {
+        return Err(Error::new("clCreateProgramWithBinary", CLStatus::CL_INVALID_VALUE)
+            .with_message(format!("got {} binaries for {} devices",
+                binaries.len(), devices.len())));
+    }
+    unsafe {
+        let mut err = 0;
+        let ids: Vec<_> = devices.iter().map(|d| d.0).collect();
+        let lengths: Vec<_> = binaries.iter().map(|b| b.len() as libc::size_t).collect();
+        let pointers: Vec<_> = binaries.iter().map(|b| b.as_ptr()).collect();
+        let program = ffi::clCreateProgramWithBinary(
+            context.0, ids.len() as cl::cl_uint, ids.as_ptr(), lengths.as_ptr(),
+            pointers.as_ptr(), ptr::null_mut(), &mut err);
+        try!(check_status("clCreateProgramWithBinary", err));
+        Ok(Program(program))
+    }
+}
+
+/// Returns the binary `program` was built into for each of its devices, suitable for
+/// `create_program_with_binary`. Devices the program hasn't been built for get an empty
+/// binary.
+pub fn get_program_binaries(program: &Program) -> Result<Vec<(DeviceId, Vec<u8>)>> {
+    let devices = try!(get_program_info(program, ProgramInfoDevices));
+    let sizes = try!(get_program_info(program, ProgramInfoBinarySizes));
+    unsafe {
+        let mut binaries: Vec<Vec<u8>> =
+            sizes.iter().map(|&size| repeat(0).take(size).collect()).collect();
+        // a null entry tells the driver to skip that device.
+        let mut pointers: Vec<*mut u8> = binaries.iter_mut()
+            .map(|b| if b.is_empty() { ptr::null_mut() } else { b.as_mut_ptr() })
+            .collect();
+        let res = cl::ll::clGetProgramInfo(
+            program.0, cl::CL_PROGRAM_BINARIES,
+            (pointers.len() * mem::size_of::<*mut u8>()) as libc::size_t,
+            pointers.as_mut_ptr() as *mut _, ptr::null_mut());
+        try!(check_status("clGetProgramInfo", res));
+        Ok(devices.into_iter().zip(binaries).collect())
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+    Note,
+}
+
+/// A single compiler message picked out of a build log.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub line: u32,
+    /// Not every compiler reports a column.
+    pub column: Option<u32>,
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl Diagnostic {
+    /// Parses the clang-style `<file>:<line>:<column>: <severity>: <message>` lines most
+    /// vendors emit, as well as the EDG-style `"<file>", line <line>: <severity>: <message>`.
+    pub fn parse(line: &str) -> Option<Diagnostic> {
+        let parts: Vec<_> = line.split(':').collect();
+        for i in 0..parts.len().saturating_sub(2) {
+            if let (Ok(line), Ok(column)) = (parts[i].trim().parse(), parts[i + 1].trim().parse()) {
+                // Intel prefixes a file index (`1:3:5: error: ...`), so a numeric pair that
+                // isn't followed by a severity may just be too early.
+                if let Some(diagnostic) = Diagnostic::from_tail(
+                    line, Some(column), &parts[i + 2..].join(":"))
+                {
+                    return Some(diagnostic);
+                }
+            }
+        }
+        if let Some(idx) = line.find(", line ") {
+            let tail = &line[idx + ", line ".len()..];
+            if let Some(colon) = tail.find(':') {
+                if let Ok(line) = tail[..colon].trim().parse() {
+                    return Diagnostic::from_tail(line, None, &tail[colon + 1..]);
+                }
+            }
+        }
+        None
+    }
+
+    fn from_tail(line: u32, column: Option<u32>, tail: &str) -> Option<Diagnostic> {
+        let mut pieces = tail.splitn(2, ':');
+        let severity = match pieces.next().map(|s| s.trim()) {
+            Some("error") | Some("fatal error") => Severity::Error,
+            Some("warning") => Severity::Warning,
+            Some("note") => Severity::Note,
+            _ => return None,
+        };
+        let message = pieces.next().unwrap_or("").trim().to_string();
+        Some(Diagnostic { line: line, column: column, severity: severity, message: message })
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+            Severity::Note => "note",
+        };
+        match self.column {
+            Some(column) => write!(f, "line {}, column {}: {}: {}",
+                self.line, column, severity, self.message),
+            None => write!(f, "line {}: {}: {}", self.line, severity, self.message),
+        }
+    }
+}
+
+/// The outcome of building a program for one device.
+#[derive(Debug, Clone)]
+pub struct DeviceBuildLog {
+    pub device: DeviceId,
+    pub device_name: String,
+    pub status: BuildStatus,
+    pub options: String,
+    pub log: String,
+}
+
+impl DeviceBuildLog {
+    pub fn new(program: &Program, device: DeviceId) -> Result<DeviceBuildLog> {
+        Ok(DeviceBuildLog {
+            device: device,
+            device_name: try!(get_device_info(device, DeviceInfoString::Name)),
+            status: try!(get_program_build_info(program, device, ProgramBuildInfoStatus)),
+            options: try!(get_program_build_info(
+                program, device, ProgramBuildInfoString::Options)),
+            log: try!(get_program_build_info(program, device, ProgramBuildInfoString::Log)),
+        })
+    }
+
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        self.log.lines().filter_map(Diagnostic::parse).collect()
+    }
+}
+
+impl fmt::Display for DeviceBuildLog {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(writeln!(f, "device \"{}\" (status: {:?}, options: {:?}):",
+            self.device_name, self.status, self.options));
+        let diagnostics = self.diagnostics();
+        if diagnostics.is_empty() {
+            for line in self.log.lines() {
+                try!(writeln!(f, "    {}", line));
+            }
+        } else {
+            for diagnostic in diagnostics {
+                try!(writeln!(f, "    {}", diagnostic));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Collects the build logs of `devices` (or every device of `program`, if `devices` is empty).
+/// Logs that can't be retrieved are left out.
+fn collect_build_logs(program: &Program, devices: &[DeviceId]) -> Vec<DeviceBuildLog> {
+    let devices = if devices.is_empty() {
+        get_program_info(program, ProgramInfoDevices).unwrap_or(Vec::new())
+    } else {
+        devices.to_vec()
+    };
+    devices.into_iter()
+        .filter_map(|device| DeviceBuildLog::new(program, device).ok())
+        .collect()
+}
+
+/// Returned by `clCompileProgram` and `clLinkProgram` when the compiler or linker rejects
+/// its input. Not part of `CLStatus`, since they're from OpenCL 1.2.
+pub const COMPILE_PROGRAM_FAILURE: cl::cl_int = -15;
+pub const LINK_PROGRAM_FAILURE: cl::cl_int = -17;
+
+fn options_cstring(function: &'static str, options: &str) -> Result<CString> {
+    CString::new(options).map_err(|_| {
+        Error::new(function, CLStatus::CL_INVALID_BUILD_OPTIONS)
+            .with_message("build options contain a null byte")
+    })
+}
+
+/// Builds `program` with `options` for each device in `devices`, or for every device
+/// associated with the program if `devices` is empty. Blocks until the build is finished.
+/// If the compiler rejects the source, the error carries the build log of every device.
+pub fn build_program(program: &Program, devices: &[DeviceId], options: &str) -> Result<()> {
+    unsafe {
+        let options = try!(options_cstring("clBuildProgram", options));
+        let ids: Vec<_> = devices.iter().map(|d| d.0).collect();
+        let ids_ptr = if ids.is_empty() { ptr::null() } else { ids.as_ptr() };
+        let res = ffi::clBuildProgram(
+            program.0, ids.len() as cl::cl_uint, ids_ptr, options.as_ptr(), None,
+            ptr::null_mut());
+        match check_status("clBuildProgram", res) {
+            Err(ref err) if err.status() == Status::Known(CLStatus::CL_BUILD_PROGRAM_FAILURE) =>
+                Err(err.clone().with_build_logs(collect_build_logs(program, devices))),
+            other => other,
+        }
+    }
+}
+
+/// Compiles the source of `program` into an object that `link_program` can link, without
+/// linking it. `headers` are the programs that `#include "<name>"` in the source refers to,
+/// each under the name given with it. As with `build_program`, an empty `devices` means
+/// every device of the program, and a rejected source gives an error carrying the build
+/// logs.
+pub fn compile_program(program: &Program, devices: &[DeviceId], options: &str,
+    headers: &[(&str, &Program)])
+    -> Result<()>
+{
+    unsafe {
+        let options = try!(options_cstring("clCompileProgram", options));
+        let mut names = Vec::with_capacity(headers.len());
+        for &(name, _) in headers.iter() {
+            match CString::new(name) {
+                Ok(name) => names.push(name),
+                Err(_) => return Err(Error::new("clCompileProgram", CLStatus::CL_INVALID_VALUE)
+                    .with_message(format!("header name {:?} contains a null byte", name))),
+            }
+        }
+        let name_ptrs: Vec<_> = names.iter().map(|name| name.as_ptr()).collect();
+        let header_programs: Vec<_> = headers.iter().map(|&(_, program)| program.0).collect();
+        let (name_ptrs_ptr, header_programs_ptr) = if headers.is_empty() {
+            (ptr::null(), ptr::null())
+        } else {
+            (name_ptrs.as_ptr(), header_programs.as_ptr())
+        };
+        let ids: Vec<_> = devices.iter().map(|d| d.0).collect();
+        let ids_ptr = if ids.is_empty() { ptr::null() } else { ids.as_ptr() };
+        let res = ffi::clCompileProgram(
+            program.0, ids.len() as cl::cl_uint, ids_ptr, options.as_ptr(),
+            headers.len() as cl::cl_uint, header_programs_ptr, name_ptrs_ptr, None,
+            ptr::null_mut());
+        match check_status("clCompileProgram", res) {
+            Err(ref err) if err.status().code() == COMPILE_PROGRAM_FAILURE =>
+                Err(err.clone().with_build_logs(collect_build_logs(program, devices))),
+            other => other,
+        }
+    }
+}
+
+/// Links compiled `programs` (see `compile_program`) into a new program for `devices`, or for
+/// every device of `context` if `devices` is empty. If the linker fails, the error carries
+/// its log for every device.
+pub fn link_program(context: &Context, devices: &[DeviceId], options: &str,
+    programs: &[&Program])
+    -> Result<Program>
+{
+    unsafe {
+        let mut err = 0;
+        let options = try!(options_cstring("clLinkProgram", options));
+        let inputs: Vec<_> = programs.iter().map(|program| program.0).collect();
+        let ids: Vec<_> = devices.iter().map(|d| d.0).collect();
+        let ids_ptr = if ids.is_empty() { ptr::null() } else { ids.as_ptr() };
+        let program = ffi::clLinkProgram(
+            context.0, ids.len() as cl::cl_uint, ids_ptr, options.as_ptr(),
+            inputs.len() as cl::cl_uint, inputs.as_ptr(), None, ptr::null_mut(), &mut err);
+        if let Err(err) = check_status("clLinkProgram", err) {
+            // a failed link still hands back a program, purely so its log can be read.
+            if err.status().code() == LINK_PROGRAM_FAILURE && !program.is_null() {
+                let program = Program(program);
+                return Err(err.with_build_logs(collect_build_logs(&program, devices)));
+            }
+            return Err(err);
+        }
+        Ok(Program(program))
+    }
+}
+
+impl Program {
+    pub fn try_clone(&self) -> Result<Program> {
+        unsafe {
+            try!(check_status("clRetainProgram", cl::ll::clRetainProgram(self.0)));
+            Ok(Program(self.0))
+        }
+    }
+}
+
+impl Clone for Program {
+    fn clone(&self) -> Program {
+        match self.try_clone() {
+            Ok(program) => program,
+            Err(err) => panic!(
+                "Rascal: Failed to increment OpenCL program refcount! (Error: {})", err),
+        }
+    }
+}
+
+impl Drop for Program {
+    fn drop(&mut self) {
+        unsafe {
+            match check_status("clReleaseProgram", cl::ll::clReleaseProgram(self.0)) {
+                Ok(()) => { }
+                Err(err) => panic!(
+                    "Rascal: Failed to decrement OpenCL program refcount! (Error: {})", err)
+            }
+        }
+    }
+}
+
+pub fn create_kernel(program: &Program, name: &str) -> Result<Kernel> {
+    unsafe {
+        let name = match CString::new(name) {
+            Ok(name) => name,
+            Err(_) => return Err(Error::new("clCreateKernel", CLStatus::CL_INVALID_KERNEL_NAME)
+                .with_message("kernel name contains a null byte")),
+        };
+        let mut err = 0;
+        let kernel = cl::ll::clCreateKernel(program.0, name.as_ptr(), &mut err);
+        try!(check_status("clCreateKernel", err));
+        Ok(Kernel(kernel))
+    }
+}
+
+/// Creates a kernel for every kernel function in `program`.
+pub fn create_kernels_in_program(program: &Program) -> Result<Vec<Kernel>> {
+    unsafe {
+        let mut num_kernels = 0;
+        let res = cl::ll::clCreateKernelsInProgram(
+            program.0, 0, ptr::null_mut(), &mut num_kernels);
+        try!(check_status("clCreateKernelsInProgram", res));
+        let mut kernels: Vec<_> = repeat(0 as *mut _).take(num_kernels as usize).collect();
+        let res = cl::ll::clCreateKernelsInProgram(
+            program.0, kernels.len() as cl::cl_uint, kernels.as_mut_ptr(), ptr::null_mut());
+        try!(check_status("clCreateKernelsInProgram", res));
+        Ok(kernels.iter().map(|ptr| Kernel(*ptr)).collect())
+    }
+}
+
+/// A value that can be passed to `clSetKernelArg`.
+///
+/// Unsafe to implement because the driver reads `arg_size()` bytes from `arg_ptr()`, which
+/// must therefore be either valid for that many bytes or null (for `__local` arguments).
+pub unsafe trait KernelArg {
+    fn arg_size(&self) -> usize;
+    fn arg_ptr(&self) -> *const libc::c_void;
+}
+
+macro_rules! scalar_kernel_arg {
+    ($($Type:ty),*) => {
+        $(
+            unsafe impl KernelArg for $Type {
+                fn arg_size(&self) -> usize {
+                    mem::size_of::<$Type>()
+                }
+
+                fn arg_ptr(&self) -> *const libc::c_void {
+                    self as *const $Type as *const _
+                }
+            }
+        )*
+    }
+}
+
+scalar_kernel_arg!(i8, u8, i16, u16, i32, u32, i64, u64, f32, f64);
+
+unsafe impl KernelArg for Mem {
+    fn arg_size(&self) -> usize {
+        mem::size_of::<cl::cl_mem>()
+    }
+
+    fn arg_ptr(&self) -> *const libc::c_void {
+        &self.0 as *const cl::cl_mem as *const _
+    }
+}
+
+unsafe impl KernelArg for Sampler {
+    fn arg_size(&self) -> usize {
+        mem::size_of::<cl::cl_sampler>()
+    }
+
+    fn arg_ptr(&self) -> *const libc::c_void {
+        &self.0 as *const cl::cl_sampler as *const _
+    }
+}
+
+/// Allocates the given number of bytes of `__local` memory for a kernel argument.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct LocalMem(pub usize);
+
+unsafe impl KernelArg for LocalMem {
+    fn arg_size(&self) -> usize {
+        self.0
+    }
+
+    fn arg_ptr(&self) -> *const libc::c_void {
+        ptr::null()
+    }
+}
+
+impl Kernel {
+    pub fn set_arg<A: KernelArg + ?Sized>(&self, index: u32, arg: &A) -> Result<()> {
+        unsafe {
+            let res = cl::ll::clSetKernelArg(
+                self.0, index as cl::cl_uint, arg.arg_size() as libc::size_t, arg.arg_ptr());
+            check_status("clSetKernelArg", res)
+        }
+    }
+
+    pub fn try_clone(&self) -> Result<Kernel> {
+        unsafe {
+            try!(check_status("clRetainKernel", cl::ll::clRetainKernel(self.0)));
+            Ok(Kernel(self.0))
+        }
+    }
+}
+
+impl Clone for Kernel {
+    fn clone(&self) -> Kernel {
+        match self.try_clone() {
+            Ok(kernel) => kernel,
+            Err(err) => panic!(
+                "Rascal: Failed to increment OpenCL kernel refcount! (Error: {})", err),
+        }
+    }
+}
+
+impl Drop for Kernel {
+    fn drop(&mut self) {
+        unsafe {
+            match check_status("clReleaseKernel", cl::ll::clReleaseKernel(self.0)) {
+                Ok(()) => { }
+                Err(err) => panic!(
+                    "Rascal: Failed to decrement OpenCL kernel refcount! (Error: {})", err)
+            }
+        }
+    }
+}
+
+/// The execution status of the command an event is attached to.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum EventStatus {
+    Queued,
+    Submitted,
+    Running,
+    Complete,
+    /// The command was abnormally terminated; holds the (negative) error code.
+    Error(cl::cl_int),
+}
+
+impl EventStatus {
+    fn from_raw(status: cl::cl_int) -> EventStatus {
+        match status {
+            cl::CL_QUEUED => EventStatus::Queued,
+            cl::CL_SUBMITTED => EventStatus::Submitted,
+            cl::CL_RUNNING => EventStatus::Running,
+            cl::CL_COMPLETE => EventStatus::Complete,
+            error => EventStatus::Error(error),
+        }
+    }
+}
+
+/// An event whose status is set from the host, so device work can wait on host-side
+/// conditions. Derefs to an `Event`, so it can go in wait lists.
+#[derive(Debug, Clone)]
+pub struct UserEvent(Event);
+
+pub fn create_user_event(context: &Context) -> Result<UserEvent> {
+    unsafe {
+        let mut err = 0;
+        let event = ffi::clCreateUserEvent(context.0, &mut err);
+        try!(check_status("clCreateUserEvent", err));
+        Ok(UserEvent(Event(event)))
+    }
+}
+
+impl UserEvent {
+    /// Marks the event as complete, letting commands waiting on it run.
+    pub fn complete(&self) -> Result<()> {
+        unsafe {
+            check_status(
+                "clSetUserEventStatus", ffi::clSetUserEventStatus((self.0).0, cl::CL_COMPLETE))
+        }
+    }
+
+    /// Marks the event as failed with `error`, which must be negative. Commands waiting on it
+    /// are terminated.
+    pub fn fail(&self, error: cl::cl_int) -> Result<()> {
+        if error >= 0 {
+            return Err(Error::new("clSetUserEventStatus", CLStatus::CL_INVALID_VALUE)
+                .with_message("user event error codes must be negative"));
+        }
+        unsafe {
+            check_status("clSetUserEventStatus", ffi::clSetUserEventStatus((self.0).0, error))
+        }
+    }
+
+    pub fn as_event(&self) -> &Event {
+        &self.0
+    }
+}
+
+impl Deref for UserEvent {
+    type Target = Event;
+    fn deref(&self) -> &Event {
+        &self.0
+    }
+}
+
+impl From<UserEvent> for Event {
+    fn from(event: UserEvent) -> Event {
+        event.0
+    }
+}
+
+type CompletionCallback = Box<dyn FnOnce(EventStatus) + Send>;
+
+extern "C" fn event_completion_handler(event: cl::cl_event, status: cl::cl_int,
+    user_data: *mut libc::c_void)
+{
+    let _ = event;
+    unsafe {
+        let callback = Box::from_raw(user_data as *mut CompletionCallback);
+        // unwinding into the driver is undefined behaviour, so any panic stops here.
+        let _ = panic::catch_unwind(panic::AssertUnwindSafe(move || {
+            callback(EventStatus::from_raw(status))
+        }));
+    }
+}
+
+/// Blocks until every event in `events` has completed.
+pub fn wait_for_events(events: &[Event]) -> Result<()> {
+    if events.is_empty() {
+        return Ok(());
+    }
+    unsafe {
+        let wait_list = RawWaitList::new(events);
+        check_status(
+            "clWaitForEvents", cl::ll::clWaitForEvents(wait_list.len(), wait_list.as_ptr()))
+    }
+}
+
+/// Device timestamps, in nanoseconds, recorded for the command an event is attached to.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ProfilingInfo {
+    pub queued: u64,
+    pub submit: u64,
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ProfilingInfo {
+    /// How long the command spent waiting between being enqueued and starting to execute.
+    pub fn wait_time(&self) -> Duration {
+        Duration::from_nanos(self.start.saturating_sub(self.queued))
+    }
+
+    /// How long the command took to execute.
+    pub fn execution_time(&self) -> Duration {
+        Duration::from_nanos(self.end.saturating_sub(self.start))
+    }
+
+    /// How long it took between the command being enqueued and it finishing.
+    pub fn total_time(&self) -> Duration {
+        Duration::from_nanos(self.end.saturating_sub(self.queued))
+    }
+}
+
+unsafe fn get_event_profiling_counter(event: &Event, info: cl::cl_profiling_info)
+    -> Result<u64>
+{
+    let mut counter: cl::cl_ulong = 0;
+    let res = cl::ll::clGetEventProfilingInfo(
+        event.0, info, mem::size_of::<cl::cl_ulong>() as libc::size_t,
+        &mut counter as *mut _ as *mut _, ptr::null_mut());
+    try!(check_status("clGetEventProfilingInfo", res));
+    Ok(counter as u64)
+}
+
+impl Event {
+    /// Blocks until the event has completed.
+    pub fn wait(&self) -> Result<()> {
+        unsafe {
+            check_status("clWaitForEvents", cl::ll::clWaitForEvents(1, &self.0))
+        }
+    }
+
+    pub fn status(&self) -> Result<EventStatus> {
+        unsafe {
+            let mut status: cl::cl_int = 0;
+            let res = cl::ll::clGetEventInfo(
+                self.0, cl::CL_EVENT_COMMAND_EXECUTION_STATUS,
+                mem::size_of::<cl::cl_int>() as libc::size_t,
+                &mut status as *mut _ as *mut _, ptr::null_mut());
+            try!(check_status("clGetEventInfo", res));
+            Ok(EventStatus::from_raw(status))
+        }
+    }
+
+    /// Returns the profiling timestamps of the event's command. Fails with
+    /// `CL_PROFILING_INFO_NOT_AVAILABLE` if the command's queue wasn't created with
+    /// `queue_properties::PROFILING_ENABLE`, or if the command hasn't completed yet.
+    pub fn profiling_info(&self) -> Result<ProfilingInfo> {
+        unsafe {
+            // User events don't belong to a queue, and so have a null one here.
+            let mut queue: cl::cl_command_queue = ptr::null_mut();
+            let res = cl::ll::clGetEventInfo(
+                self.0, cl::CL_EVENT_COMMAND_QUEUE,
+                mem::size_of::<cl::cl_command_queue>() as libc::size_t,
+                &mut queue as *mut _ as *mut _, ptr::null_mut());
+            try!(check_status("clGetEventInfo", res));
+            if queue.is_null() {
+                return Err(Error::new(
+                    "clGetEventProfilingInfo", CLStatus::CL_PROFILING_INFO_NOT_AVAILABLE)
+                    .with_message("user events have no profiling information"));
+            }
+            let mut properties: cl::cl_command_queue_properties = 0;
+            let res = cl::ll::clGetCommandQueueInfo(
+                queue, cl::CL_QUEUE_PROPERTIES,
+                mem::size_of::<cl::cl_command_queue_properties>() as libc::size_t,
+                &mut properties as *mut _ as *mut _, ptr::null_mut());
+            try!(check_status("clGetCommandQueueInfo", res));
+            if properties & cl::CL_QUEUE_PROFILING_ENABLE == 0 {
+                return Err(Error::new(
+                    "clGetEventProfilingInfo", CLStatus::CL_PROFILING_INFO_NOT_AVAILABLE)
+                    .with_message("the event's queue wasn't created with PROFILING_ENABLE"));
+            }
+            Ok(ProfilingInfo {
+                queued: try!(get_event_profiling_counter(self, cl::CL_PROFILING_COMMAND_QUEUED)),
+                submit: try!(get_event_profiling_counter(self, cl::CL_PROFILING_COMMAND_SUBMIT)),
+                start: try!(get_event_profiling_counter(self, cl::CL_PROFILING_COMMAND_START)),
+                end: try!(get_event_profiling_counter(self, cl::CL_PROFILING_COMMAND_END)),
+            })
+        }
+    }
+
+    /// Calls `callback` with the final status of the event once it has completed or failed.
+    /// The callback runs on a driver thread, so it should be quick and mustn't block on other
+    /// OpenCL commands.
+    pub fn on_complete<F: FnOnce(EventStatus) + Send + 'static>(&self, callback: F)
+        -> Result<()>
+    {
+        unsafe {
+            let callback: Box<CompletionCallback> = Box::new(Box::new(callback));
+            let user_data = Box::into_raw(callback);
+            let res = ffi::clSetEventCallback(
+                self.0, cl::CL_COMPLETE, Some(event_completion_handler), user_data as *mut _);
+            match check_status("clSetEventCallback", res) {
+                Ok(()) => Ok(()),
+                Err(err) => {
+                    // the driver never took ownership, so it's still ours to free.
+                    drop(Box::from_raw(user_data));
+                    Err(err)
+                }
+            }
+        }
+    }
+
+    pub fn try_clone(&self) -> Result<Event> {
+        unsafe {
+            try!(check_status("clRetainEvent", cl::ll::clRetainEvent(self.0)));
+            Ok(Event(self.0))
+        }
+    }
+}
+
+impl Clone for Event {
+    fn clone(&self) -> Event {
+        match self.try_clone() {
+            Ok(event) => event,
+            Err(err) => panic!(
+                "Rascal: Failed to increment OpenCL event refcount! (Error: {})", err),
+        }
+    }
+}
+
+impl Drop for Event {
+    fn drop(&mut self) {
+        unsafe {
+            match check_status("clReleaseEvent", cl::ll::clReleaseEvent(self.0)) {
+                Ok(()) => { }
+                Err(err) => panic!(
+                    "Rascal: Failed to decrement OpenCL event refcount! (Error: {})", err)
+            }
+        }
+    }
+}
+
+struct EventFutureState {
+    status: Option<EventStatus>,
+    waker: Option<Waker>,
+}
+
+fn lock_state(state: &Mutex<EventFutureState>) -> MutexGuard<EventFutureState> {
+    // the state is only ever assigned to, so it's still consistent after a poisoning panic.
+    match state.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+/// Resolves once an event has completed, failing if its command was terminated. Created by
+/// awaiting an `Event`. The completion callback is registered on the first poll, and wakes
+/// the task from the driver's thread, so no executor thread is tied up while waiting.
+pub struct EventFuture {
+    event: Event,
+    state: Option<Arc<Mutex<EventFutureState>>>,
+}
+
+impl EventFuture {
+    pub fn event(&self) -> &Event {
+        &self.event
+    }
+}
+
+impl Future for EventFuture {
+    type Output = Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<Result<()>> {
+        let this = &mut *self;
+        if this.state.is_none() {
+            let state = Arc::new(Mutex::new(EventFutureState {
+                status: None,
+                waker: Some(cx.waker().clone()),
+            }));
+            let callback_state = state.clone();
+            let res = this.event.on_complete(move |status| {
+                let waker = {
+                    let mut state = lock_state(&callback_state);
+                    state.status = Some(status);
+                    state.waker.take()
+                };
+                if let Some(waker) = waker {
+                    waker.wake();
+                }
+            });
+            if let Err(err) = res {
+                return Poll::Ready(Err(err));
+            }
+            this.state = Some(state);
+        }
+        let mut state = match this.state {
+            Some(ref state) => lock_state(state),
+            None => unreachable!(),
+        };
+        match state.status {
+            Some(EventStatus::Error(error)) => Poll::Ready(Err(
+                Error::new("clSetEventCallback", Status::from_code(error))
+                    .with_message("the event's command was terminated"))),
+            Some(_) => Poll::Ready(Ok(())),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl IntoFuture for Event {
+    type Output = Result<()>;
+    type IntoFuture = EventFuture;
+
+    fn into_future(self) -> EventFuture {
+        EventFuture { event: self, state: None }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Diagnostic, Severity};
+
+    #[test]
+    fn parses_clang_diagnostics() {
+        let diagnostic = Diagnostic::parse(
+            "<source>:12:5: error: use of undeclared identifier 'x'").unwrap();
+        assert_eq!(diagnostic, Diagnostic {
+            line: 12,
+            column: Some(5),
+            severity: Severity::Error,
+            message: "use of undeclared identifier 'x'".to_string(),
+        });
+    }
+
+    #[test]
+    fn parses_edg_diagnostics() {
+        let diagnostic = Diagnostic::parse(
+            "\"/tmp/OCL1234.cl\", line 7: warning: variable \"y\" was declared but never \
+             referenced").unwrap();
+        assert_eq!(diagnostic.line, 7);
+        assert_eq!(diagnostic.column, None);
+        assert_eq!(diagnostic.severity, Severity::Warning);
+        assert_eq!(diagnostic.message,
+            "variable \"y\" was declared but never referenced");
+    }
+
+    #[test]
+    fn parses_intel_diagnostics() {
+        let diagnostic = Diagnostic::parse("1:3:5: error: expected ';' after expression")
+            .unwrap();
+        assert_eq!(diagnostic.line, 3);
+        assert_eq!(diagnostic.column, Some(5));
+        assert_eq!(diagnostic.severity, Severity::Error);
+        assert_eq!(diagnostic.message, "expected ';' after expression");
+    }
+
+    #[test]
+    fn keeps_colons_in_messages() {
+        let diagnostic = Diagnostic::parse("<source>:1:2: note: candidate: foo(int)").unwrap();
+        assert_eq!(diagnostic.severity, Severity::Note);
+        assert_eq!(diagnostic.message, "candidate: foo(int)");
+    }
+
+    #[test]
+    fn ignores_other_lines() {
+        assert_eq!(Diagnostic::parse("Compilation started"), None);
+        assert_eq!(Diagnostic::parse("    x = 1;"), None);
+        assert_eq!(Diagnostic::parse("12:34: something else"), None);
+    }
+}